@@ -0,0 +1,562 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::io;
+use std::io::{Read, Write};
+use std::str;
+
+use super::*;
+use ns::*;
+
+// Layout (all integers are LEB128 varints unless noted):
+//   magic     b"RTTB"
+//   version   u16, little endian
+//   kind      u8: KIND_NODE or KIND_RULESET
+//   strings   count, then count * (len, UTF-8 bytes)
+//   payload   a node, or count * (lhs node, rhs node, meta)
+// A node is a tag byte followed by its fields; symbols are indices into the
+// string table, so ids are re-interned into whatever Namespace loads them.
+// Rule metadata isn't made of symbols, so its strings are written inline:
+//   meta      enabled flag, name, count * (key, value), location flag,
+//             then if set: file, line, column
+// where a flag is a 0 or 1 byte, a string is (len, UTF-8 bytes) and an
+// optional string is 0 for none or (len + 1, UTF-8 bytes).
+
+pub const MAGIC: &[u8; 4] = b"RTTB";
+pub const VERSION: u16 = 2;
+
+const KIND_NODE: u8 = 0;
+const KIND_RULESET: u8 = 1;
+
+const TAG_ATOM: u8 = 0;
+const TAG_GROUP: u8 = 1;
+const TAG_MATCHPOINT: u8 = 2;
+const TAG_SEQUENCE: u8 = 3;
+const TAG_CONJUNCTOR: u8 = 4;
+const TAG_DISJUNCTOR: u8 = 5;
+const TAG_NEGATOR: u8 = 6;
+const TAG_SPLICEPAIR: u8 = 7;
+const TAG_NONODE: u8 = 8;
+
+const MAX_DEPTH: usize = 4096;
+
+#[derive(Debug)]
+pub enum BinError {
+	Io(io::Error),
+	BadMagic,
+	BadVersion(u16),
+	BadKind(u8),
+	BadTag(usize, u8),
+	BadFlag(usize, u8),
+	BadString(usize),
+	BadSymbol(usize, usize),
+	UnknownSymbol(usize),
+	Truncated,
+	TooDeep,
+	Overflow(usize),
+	TrailingBytes(usize),
+}
+
+impl fmt::Display for BinError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match *self {
+			BinError::Io(ref e) => write!(f, "I/O error: {}", e),
+			BinError::BadMagic => write!(f, "Not a compiled rtt file"),
+			BinError::BadVersion(v) => write!(f, "Unsupported format version {} (expected {})", v, VERSION),
+			BinError::BadKind(k) => write!(f, "Unexpected payload kind {}", k),
+			BinError::BadTag(pos, t) => write!(f, "Unknown node tag {} at byte {}", t, pos),
+			BinError::BadFlag(pos, b) => write!(f, "Expected a flag (0 or 1) but found {} at byte {}", b, pos),
+			BinError::BadString(pos) => write!(f, "Invalid UTF-8 in string table at byte {}", pos),
+			BinError::BadSymbol(pos, idx) => write!(f, "String index {} out of range at byte {}", idx, pos),
+			BinError::UnknownSymbol(id) => write!(f, "Symbol {} is not in the namespace", id),
+			BinError::Truncated => write!(f, "Unexpected end of data"),
+			BinError::TooDeep => write!(f, "Tree nesting exceeds {} levels", MAX_DEPTH),
+			BinError::Overflow(pos) => write!(f, "Integer overflow at byte {}", pos),
+			BinError::TrailingBytes(n) => write!(f, "{} unexpected bytes after payload", n),
+		}
+	}
+}
+
+impl From<io::Error> for BinError {
+	fn from(e: io::Error) -> BinError {
+		BinError::Io(e)
+	}
+}
+
+fn put_varint(out: &mut Vec<u8>, mut val: usize) {
+	loop {
+		let byte = (val & 0x7f) as u8;
+		val >>= 7;
+		if val == 0 {
+			out.push(byte);
+			return;
+		}
+		out.push(byte | 0x80);
+	}
+}
+
+fn put_str(out: &mut Vec<u8>, s: &str) {
+	put_varint(out, s.len());
+	out.extend_from_slice(s.as_bytes());
+}
+
+fn put_opt_str(out: &mut Vec<u8>, s: &Option<String>) {
+	match *s {
+		Some(ref s) => {
+			put_varint(out, s.len() + 1);
+			out.extend_from_slice(s.as_bytes());
+		},
+		None => put_varint(out, 0),
+	}
+}
+
+fn put_meta(out: &mut Vec<u8>, meta: &RuleMeta) {
+	out.push(meta.enabled as u8);
+	put_opt_str(out, &meta.name);
+	put_varint(out, meta.attributes.len());
+	for (key, value) in &meta.attributes {
+		put_str(out, key);
+		put_opt_str(out, value);
+	}
+	match meta.location {
+		Some(ref loc) => {
+			out.push(1);
+			put_opt_str(out, &loc.file);
+			put_varint(out, loc.line);
+			put_varint(out, loc.column);
+		},
+		None => out.push(0),
+	}
+}
+
+struct Encoder<'n> {
+	ns: &'n Namespace,
+	strings: Vec<&'n str>,
+	index: HashMap<usize, usize>,
+	body: Vec<u8>,
+}
+
+impl<'n> Encoder<'n> {
+	fn new(ns: &'n Namespace) -> Encoder<'n> {
+		Encoder { ns, strings: Vec::new(), index: HashMap::new(), body: Vec::new() }
+	}
+
+	fn symbol(&mut self, id: usize) -> Result<(), BinError> {
+		let idx = match self.index.get(&id) {
+			Some(&idx) => idx,
+			None => {
+				let s = self.ns.to_str(id).ok_or(BinError::UnknownSymbol(id))?;
+				self.strings.push(s);
+				self.index.insert(id, self.strings.len() - 1);
+				self.strings.len() - 1
+			},
+		};
+		put_varint(&mut self.body, idx);
+		Ok(())
+	}
+
+	fn children(&mut self, children: &[Node]) -> Result<(), BinError> {
+		put_varint(&mut self.body, children.len());
+		for child in children {
+			self.node(child)?;
+		}
+		Ok(())
+	}
+
+	fn node(&mut self, node: &Node) -> Result<(), BinError> {
+		match *node {
+			Atom(val) => {
+				self.body.push(TAG_ATOM);
+				self.symbol(val)
+			},
+			Group(val, ref children) => {
+				self.body.push(TAG_GROUP);
+				self.symbol(val)?;
+				self.children(children)
+			},
+			MatchPoint(val) => {
+				self.body.push(TAG_MATCHPOINT);
+				self.symbol(val)
+			},
+			Sequence(val, ref children) => {
+				self.body.push(TAG_SEQUENCE);
+				self.symbol(val)?;
+				self.children(children)
+			},
+			Conjunctor(ref children) => {
+				self.body.push(TAG_CONJUNCTOR);
+				self.children(children)
+			},
+			Disjunctor(ref children) => {
+				self.body.push(TAG_DISJUNCTOR);
+				self.children(children)
+			},
+			Negator(ref inner) => {
+				self.body.push(TAG_NEGATOR);
+				self.node(inner)
+			},
+			SplicePair(start, len) => {
+				self.body.push(TAG_SPLICEPAIR);
+				put_varint(&mut self.body, start);
+				put_varint(&mut self.body, len);
+				Ok(())
+			},
+			NoNode => {
+				self.body.push(TAG_NONODE);
+				Ok(())
+			},
+		}
+	}
+
+	fn finish(self, kind: u8) -> Vec<u8> {
+		let mut out = Vec::with_capacity(self.body.len() + 16);
+		out.extend_from_slice(MAGIC);
+		out.push((VERSION & 0xff) as u8);
+		out.push((VERSION >> 8) as u8);
+		out.push(kind);
+		put_varint(&mut out, self.strings.len());
+		for s in &self.strings {
+			put_varint(&mut out, s.len());
+			out.extend_from_slice(s.as_bytes());
+		}
+		out.extend_from_slice(&self.body);
+		out
+	}
+}
+
+struct Decoder<'a> {
+	data: &'a [u8],
+	pos: usize,
+	strings: Vec<String>,
+}
+
+impl<'a> Decoder<'a> {
+	fn new(data: &'a [u8]) -> Decoder<'a> {
+		Decoder { data, pos: 0, strings: Vec::new() }
+	}
+
+	fn byte(&mut self) -> Result<u8, BinError> {
+		let b = *self.data.get(self.pos).ok_or(BinError::Truncated)?;
+		self.pos += 1;
+		Ok(b)
+	}
+
+	fn varint(&mut self) -> Result<usize, BinError> {
+		let start = self.pos;
+		let mut val = 0usize;
+		let mut shift = 0;
+		loop {
+			let b = self.byte()?;
+			let bits = usize::BITS as usize;
+			if shift >= bits || (shift > 0 && ((b & 0x7f) as usize) >> (bits - shift) != 0) {
+				return Err(BinError::Overflow(start));
+			}
+			val |= ((b & 0x7f) as usize) << shift;
+			if b & 0x80 == 0 {
+				return Ok(val);
+			}
+			shift += 7;
+		}
+	}
+
+	// Every encoded element takes at least one byte, which bounds any count.
+	fn count(&mut self) -> Result<usize, BinError> {
+		let n = self.varint()?;
+		if n > self.data.len() - self.pos {
+			return Err(BinError::Truncated);
+		}
+		Ok(n)
+	}
+
+	fn bytes_str(&mut self, len: usize) -> Result<String, BinError> {
+		if len > self.data.len() - self.pos {
+			return Err(BinError::Truncated);
+		}
+		let start = self.pos;
+		let s = str::from_utf8(&self.data[start..start + len]).map_err(|_| BinError::BadString(start))?;
+		self.pos += len;
+		Ok(s.to_string())
+	}
+
+	fn string(&mut self) -> Result<String, BinError> {
+		let len = self.varint()?;
+		self.bytes_str(len)
+	}
+
+	fn opt_string(&mut self) -> Result<Option<String>, BinError> {
+		match self.varint()? {
+			0 => Ok(None),
+			n => self.bytes_str(n - 1).map(Some),
+		}
+	}
+
+	fn flag(&mut self) -> Result<bool, BinError> {
+		let pos = self.pos;
+		match self.byte()? {
+			0 => Ok(false),
+			1 => Ok(true),
+			b => Err(BinError::BadFlag(pos, b)),
+		}
+	}
+
+	fn meta(&mut self) -> Result<RuleMeta, BinError> {
+		let enabled = self.flag()?;
+		let name = self.opt_string()?;
+		let n = self.count()?;
+		let mut attributes = Vec::with_capacity(n);
+		for _ in 0..n {
+			let key = self.string()?;
+			attributes.push((key, self.opt_string()?));
+		}
+		let location = if self.flag()? {
+			let file = self.opt_string()?;
+			let line = self.varint()?;
+			Some(Location { file, line, column: self.varint()? })
+		} else { None };
+		Ok(RuleMeta { name, location, attributes, enabled })
+	}
+
+	fn header(&mut self) -> Result<u8, BinError> {
+		if self.data.len() < MAGIC.len() || &self.data[..MAGIC.len()] != MAGIC {
+			return Err(BinError::BadMagic);
+		}
+		self.pos = MAGIC.len();
+		let version = self.byte()? as u16 | (self.byte()? as u16) << 8;
+		if version != VERSION {
+			return Err(BinError::BadVersion(version));
+		}
+		let kind = self.byte()?;
+		if kind != KIND_NODE && kind != KIND_RULESET {
+			return Err(BinError::BadKind(kind));
+		}
+		let count = self.count()?;
+		for _ in 0..count {
+			let s = self.string()?;
+			self.strings.push(s);
+		}
+		Ok(kind)
+	}
+
+	fn symbol(&mut self) -> Result<usize, BinError> {
+		let pos = self.pos;
+		let idx = self.varint()?;
+		if idx < self.strings.len() { Ok(idx) } else { Err(BinError::BadSymbol(pos, idx)) }
+	}
+
+	fn children(&mut self, depth: usize) -> Result<Vec<Node>, BinError> {
+		let n = self.count()?;
+		let mut children = Vec::with_capacity(n);
+		for _ in 0..n {
+			children.push(self.node(depth + 1)?);
+		}
+		Ok(children)
+	}
+
+	fn node(&mut self, depth: usize) -> Result<Node, BinError> {
+		if depth > MAX_DEPTH {
+			return Err(BinError::TooDeep);
+		}
+		let pos = self.pos;
+		Ok(match self.byte()? {
+			TAG_ATOM => Atom(self.symbol()?),
+			TAG_GROUP => {
+				let name = self.symbol()?;
+				Group(name, self.children(depth)?)
+			},
+			TAG_MATCHPOINT => MatchPoint(self.symbol()?),
+			TAG_SEQUENCE => {
+				let name = self.symbol()?;
+				Sequence(name, self.children(depth)?)
+			},
+			TAG_CONJUNCTOR => Conjunctor(self.children(depth)?),
+			TAG_DISJUNCTOR => Disjunctor(self.children(depth)?),
+			TAG_NEGATOR => Negator(Box::new(self.node(depth + 1)?)),
+			TAG_SPLICEPAIR => {
+				let start = self.varint()?;
+				SplicePair(start, self.varint()?)
+			},
+			TAG_NONODE => NoNode,
+			tag => return Err(BinError::BadTag(pos, tag)),
+		})
+	}
+
+	// Only once all of the data has checked out does the string table go into
+	// the Namespace, giving the ids to swap in for the table indices.
+	fn finish(self, ns: &mut Namespace) -> Result<Vec<usize>, BinError> {
+		if self.pos != self.data.len() {
+			return Err(BinError::TrailingBytes(self.data.len() - self.pos));
+		}
+		Ok(self.strings.iter().map(|s| ns.to_int(s)).collect())
+	}
+}
+
+pub fn encode_node(node: &Node, ns: &Namespace) -> Result<Vec<u8>, BinError> {
+	let mut enc = Encoder::new(ns);
+	enc.node(node)?;
+	Ok(enc.finish(KIND_NODE))
+}
+
+// Replaces the string table indices a node was decoded with by symbol ids.
+fn intern(node: &Node, ids: &[usize]) -> Node {
+	let all = |v: &Vec<Node>| v.iter().map(|n| intern(n, ids)).collect();
+	match *node {
+		Atom(idx) => Atom(ids[idx]),
+		Group(idx, ref v) => Group(ids[idx], all(v)),
+		MatchPoint(idx) => MatchPoint(ids[idx]),
+		Sequence(idx, ref v) => Sequence(ids[idx], all(v)),
+		Conjunctor(ref v) => Conjunctor(all(v)),
+		Disjunctor(ref v) => Disjunctor(all(v)),
+		Negator(ref n) => Negator(Box::new(intern(n, ids))),
+		SplicePair(..) | NoNode => node.clone(),
+	}
+}
+
+pub fn decode_node(data: &[u8], ns: &mut Namespace) -> Result<Node, BinError> {
+	let mut dec = Decoder::new(data);
+	let kind = dec.header()?;
+	if kind != KIND_NODE {
+		return Err(BinError::BadKind(kind));
+	}
+	let node = dec.node(0)?;
+	let ids = dec.finish(ns)?;
+	Ok(intern(&node, &ids))
+}
+
+pub fn encode_ruleset(rules: &RuleSet, ns: &Namespace) -> Result<Vec<u8>, BinError> {
+	let mut enc = Encoder::new(ns);
+	put_varint(&mut enc.body, rules.len());
+	for rule in rules {
+		enc.node(&rule.lhs)?;
+		enc.node(&rule.rhs)?;
+		put_meta(&mut enc.body, &rule.meta);
+	}
+	Ok(enc.finish(KIND_RULESET))
+}
+
+pub fn decode_ruleset(data: &[u8], ns: &mut Namespace) -> Result<RuleSet, BinError> {
+	let mut dec = Decoder::new(data);
+	let kind = dec.header()?;
+	if kind != KIND_RULESET {
+		return Err(BinError::BadKind(kind));
+	}
+	let n = dec.count()?;
+	let mut rules = RuleSet::with_capacity(n);
+	for _ in 0..n {
+		let lhs = dec.node(0)?;
+		let rhs = dec.node(0)?;
+		let mut rule = Rule::new(lhs, rhs);
+		rule.meta = dec.meta()?;
+		rules.push(rule);
+	}
+	let ids = dec.finish(ns)?;
+	for rule in &mut rules {
+		rule.lhs = intern(&rule.lhs, &ids);
+		rule.rhs = intern(&rule.rhs, &ids);
+	}
+	Ok(rules)
+}
+
+pub fn save_ruleset<W: Write>(w: &mut W, rules: &RuleSet, ns: &Namespace) -> Result<(), BinError> {
+	w.write_all(&encode_ruleset(rules, ns)?)?;
+	Ok(())
+}
+
+pub fn load_ruleset<R: Read>(r: &mut R, ns: &mut Namespace) -> Result<RuleSet, BinError> {
+	let mut data = Vec::new();
+	r.read_to_end(&mut data)?;
+	decode_ruleset(&data, ns)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use ttr::*;
+
+	#[test]
+	fn ruleset_round_trip() {
+		let mut ns = Namespace::new();
+		let rules = make_ttr_rules(&mut ns);
+		let mut file = Vec::new();
+		save_ruleset(&mut file, &rules, &ns).unwrap();
+
+		let mut fresh = Namespace::new();
+		fresh.to_int("shifts every id");
+		let loaded = load_ruleset(&mut &file[..], &mut fresh).unwrap();
+		assert_eq!(loaded.len(), rules.len());
+		assert_eq!(encode_ruleset(&loaded, &fresh).unwrap(), file);
+	}
+
+	#[test]
+	fn rule_meta_round_trip() {
+		let mut ns = Namespace::new();
+		let mut rules = parse_rules("[note = \"\u{e9}\", flag] tidy: 'a' -> 'b';\n'c' -> 'd';", &mut ns).unwrap();
+		rules[0].meta.location.as_mut().unwrap().file = Some("math.tt".to_string());
+		set_enabled(&mut rules, "tidy", false);
+		let data = encode_ruleset(&rules, &ns).unwrap();
+		let mut decoded = decode_ruleset(&data, &mut ns).unwrap();
+		for (a, b) in rules.iter().zip(&decoded) {
+			assert_eq!(a.meta, b.meta);
+		}
+		assert_eq!(set_enabled(&mut decoded, "tidy", true), 1);
+
+		// The last rule's meta is 7 bytes, starting with its enabled flag
+		let mut bad = data.clone();
+		let flag = bad.len() - 7;
+		assert_eq!(bad[flag], 1);
+		bad[flag] = 2;
+		match decode_ruleset(&bad, &mut ns) { Err(BinError::BadFlag(_, 2)) => (), r => panic!("{:?}", r) }
+	}
+
+	#[test]
+	fn node_round_trip() {
+		let mut ns = Namespace::new();
+		let tree = Group(ns.to_int("document"), vec![
+			Sequence(ns.to_int("s"), vec![MatchPoint(ns.to_int("x")), Atom(ns.to_int("\u{e9}t\u{e9}"))]),
+			Conjunctor(vec![Disjunctor(vec![]), Negator(Box::new(NoNode))]),
+			SplicePair(300, 0),
+		]);
+		let data = encode_node(&tree, &ns).unwrap();
+		assert_eq!(decode_node(&data, &mut ns).unwrap(), tree);
+	}
+
+	#[test]
+	fn validation() {
+		let mut ns = Namespace::new();
+		let tree = Group(ns.to_int("g"), vec![Atom(ns.to_int("a"))]);
+		let data = encode_node(&tree, &ns).unwrap();
+
+		match decode_node(b"nope", &mut ns) { Err(BinError::BadMagic) => (), r => panic!("{:?}", r) }
+		let mut bad = data.clone();
+		bad[4] = 99;
+		match decode_node(&bad, &mut ns) { Err(BinError::BadVersion(99)) => (), r => panic!("{:?}", r) }
+		match decode_ruleset(&data, &mut ns) { Err(BinError::BadKind(0)) => (), r => panic!("{:?}", r) }
+		match decode_node(&data[..data.len() - 1], &mut ns) { Err(BinError::Truncated) => (), r => panic!("{:?}", r) }
+		let mut long = data.clone();
+		long.push(0);
+		match decode_node(&long, &mut ns) { Err(BinError::TrailingBytes(1)) => (), r => panic!("{:?}", r) }
+		let mut badsym = data.clone();
+		let last = badsym.len() - 1;
+		badsym[last] = 7;
+		match decode_node(&badsym, &mut ns) { Err(BinError::BadSymbol(_, 7)) => (), r => panic!("{:?}", r) }
+		match encode_node(&Atom(1000), &ns) { Err(BinError::UnknownSymbol(1000)) => (), r => panic!("{:?}", r) }
+	}
+
+	#[test]
+	fn failed_decode_leaves_namespace_alone() {
+		let mut ns = Namespace::new();
+		let tree = Group(ns.to_int("g"), vec![Atom(ns.to_int("a"))]);
+		let data = encode_node(&tree, &ns).unwrap();
+
+		let mut fresh = Namespace::new();
+		match decode_node(&data[..data.len() - 1], &mut fresh) { Err(BinError::Truncated) => (), r => panic!("{:?}", r) }
+		let mut long = data.clone();
+		long.push(0);
+		match decode_node(&long, &mut fresh) { Err(BinError::TrailingBytes(1)) => (), r => panic!("{:?}", r) }
+		assert!(fresh.is_empty());
+
+		// Ids come from the caller's Namespace, not the file's string table
+		fresh.to_int("x");
+		let back = decode_node(&data, &mut fresh).unwrap();
+		assert_eq!(back, Group(fresh.to_int("g"), vec![Atom(fresh.to_int("a"))]));
+		assert_eq!(fresh.len(), 3);
+	}
+}
@@ -1,8 +1,19 @@
-use std::io::Read;
-use std::collections::HashMap;
+use std::char;
+use std::cmp::Reverse;
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::io;
+use std::io::BufRead;
 use std::iter::Iterator;
+use std::str;
 
+use unicode_xid::UnicodeXID;
+
+// Character classes are tried in this order, so a character listed in more
+// than one class belongs to the first: strings, punctuation, digits, then
+// identifier starts. Anything in none of them is an UnexpectedChar error.
 #[allow(non_snake_case)]
+#[derive(Debug, Clone)]
 pub struct Lexemes<'a> {
 	WS: &'a str,
 	PUNCT: &'a str,
@@ -11,15 +22,26 @@ pub struct Lexemes<'a> {
 	ESCAPES: HashMap<char, char>,
 	ESC_HEX: char,
 	ESC_OCT: char,
+	ESC_UNI: char,
 	OCT_DIGIT: &'a str,
 	DIGIT: &'a str,
 	HEX_DIGIT: &'a str,
 	IDENT_START: &'a str,
 	IDENT: &'a str,
+	UNICODE_IDENT: bool,
+	DIGIT_SEP: Option<char>,
+	RADIX_PREFIXES: bool,
+	FLOATS: bool,
+	NUM_SUFFIXES: Vec<&'a str>,
+	OPERATORS: Vec<&'a str>,
+	LINE_COMMENTS: Vec<&'a str>,
+	BLOCK_COMMENTS: Vec<(&'a str, &'a str)>,
+	NEST_COMMENTS: bool,
+	KEEP_COMMENTS: bool,
 }
 
-impl<'a> Lexemes<'a> {
-	fn default() -> Lexemes<'static> {
+impl<'a> Default for Lexemes<'a> {
+	fn default() -> Lexemes<'a> {
 		let mut l = Lexemes {
 			WS: " \t\r\n",
 			PUNCT: "`~!@#$%^&*()+-=[]\\{}|;:,./<>?",
@@ -28,11 +50,22 @@ impl<'a> Lexemes<'a> {
 			ESCAPES: HashMap::new(),
 			ESC_HEX: 'x',
 			ESC_OCT: '0',
+			ESC_UNI: 'u',
 			OCT_DIGIT: "01234567",
 			DIGIT: "0123456789",
 			HEX_DIGIT: "0123456789abcdefABCDEF",
 			IDENT_START: "abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ_",
 			IDENT: "abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ_0123456789",
+			UNICODE_IDENT: false,
+			DIGIT_SEP: Some('_'),
+			RADIX_PREFIXES: true,
+			FLOATS: true,
+			NUM_SUFFIXES: Vec::new(),
+			OPERATORS: Vec::new(),
+			LINE_COMMENTS: Vec::new(),
+			BLOCK_COMMENTS: vec![("/*", "*/")],
+			NEST_COMMENTS: false,
+			KEEP_COMMENTS: false,
 		};
 		l.ESCAPES.insert('n', '\n');
 		l.ESCAPES.insert('t', '\t');
@@ -43,19 +76,355 @@ impl<'a> Lexemes<'a> {
 	}
 }
 
+impl<'a> Lexemes<'a> {
+	pub fn builder() -> LexemesBuilder<'a> {
+		LexemesBuilder { lexemes: Lexemes::default() }
+	}
+
+	fn is_ident_start(&self, c: char) -> bool {
+		char_in(self.IDENT_START, c) || (self.UNICODE_IDENT && c.is_xid_start())
+	}
+
+	fn is_ident(&self, c: char) -> bool {
+		char_in(self.IDENT, c) || (self.UNICODE_IDENT && c.is_xid_continue())
+	}
+}
+
+// Starts from the default lexemes; each setter replaces one class wholesale.
+pub struct LexemesBuilder<'a> {
+	lexemes: Lexemes<'a>,
+}
+
+impl<'a> LexemesBuilder<'a> {
+	pub fn whitespace(mut self, chars: &'a str) -> LexemesBuilder<'a> {
+		self.lexemes.WS = chars;
+		self
+	}
+
+	pub fn punctuation(mut self, chars: &'a str) -> LexemesBuilder<'a> {
+		self.lexemes.PUNCT = chars;
+		self
+	}
+
+	pub fn string_delimiters(mut self, chars: &'a str) -> LexemesBuilder<'a> {
+		self.lexemes.STR_GRP = chars;
+		self
+	}
+
+	pub fn escape_char(mut self, c: char) -> LexemesBuilder<'a> {
+		self.lexemes.ESCAPE = c;
+		self
+	}
+
+	pub fn escapes(mut self, table: HashMap<char, char>) -> LexemesBuilder<'a> {
+		self.lexemes.ESCAPES = table;
+		self
+	}
+
+	pub fn escape(mut self, from: char, to: char) -> LexemesBuilder<'a> {
+		self.lexemes.ESCAPES.insert(from, to);
+		self
+	}
+
+	pub fn hex_escape(mut self, c: char) -> LexemesBuilder<'a> {
+		self.lexemes.ESC_HEX = c;
+		self
+	}
+
+	pub fn oct_escape(mut self, c: char) -> LexemesBuilder<'a> {
+		self.lexemes.ESC_OCT = c;
+		self
+	}
+
+	// Introduces a braced escape of up to six hex digits, as in "\u{1F600}".
+	pub fn unicode_escape(mut self, c: char) -> LexemesBuilder<'a> {
+		self.lexemes.ESC_UNI = c;
+		self
+	}
+
+	pub fn digits(mut self, chars: &'a str) -> LexemesBuilder<'a> {
+		self.lexemes.DIGIT = chars;
+		self
+	}
+
+	pub fn hex_digits(mut self, chars: &'a str) -> LexemesBuilder<'a> {
+		self.lexemes.HEX_DIGIT = chars;
+		self
+	}
+
+	pub fn oct_digits(mut self, chars: &'a str) -> LexemesBuilder<'a> {
+		self.lexemes.OCT_DIGIT = chars;
+		self
+	}
+
+	pub fn ident_start(mut self, chars: &'a str) -> LexemesBuilder<'a> {
+		self.lexemes.IDENT_START = chars;
+		self
+	}
+
+	pub fn ident_continue(mut self, chars: &'a str) -> LexemesBuilder<'a> {
+		self.lexemes.IDENT = chars;
+		self
+	}
+
+	// Also accept Unicode XID_Start/XID_Continue characters in identifiers,
+	// in addition to the explicit sets above.
+	pub fn unicode_idents(mut self, on: bool) -> LexemesBuilder<'a> {
+		self.lexemes.UNICODE_IDENT = on;
+		self
+	}
+
+	// Dropped from number text when it sits between two digits, as in 1_000.
+	pub fn digit_separator(mut self, sep: Option<char>) -> LexemesBuilder<'a> {
+		self.lexemes.DIGIT_SEP = sep;
+		self
+	}
+
+	// Recognize 0x, 0o and 0b integer prefixes.
+	pub fn radix_prefixes(mut self, on: bool) -> LexemesBuilder<'a> {
+		self.lexemes.RADIX_PREFIXES = on;
+		self
+	}
+
+	// Recognize decimal fractions and exponents, as in 3.14 and 1e-9.
+	pub fn floats(mut self, on: bool) -> LexemesBuilder<'a> {
+		self.lexemes.FLOATS = on;
+		self
+	}
+
+	// Suffixes (e.g. "u32", "L") accepted directly after a number, matched
+	// longest first and only if not followed by another identifier character.
+	pub fn number_suffixes(mut self, suffixes: &[&'a str]) -> LexemesBuilder<'a> {
+		self.lexemes.NUM_SUFFIXES = suffixes.to_vec();
+		self.lexemes.NUM_SUFFIXES.sort_by_key(|suf| Reverse(suf.chars().count()));
+		self
+	}
+
+	// Multi-character operators, matched longest first after a punctuation
+	// character; e.g. with "->" and "->>" listed, "->>" lexes as one token.
+	pub fn operators(mut self, ops: &[&'a str]) -> LexemesBuilder<'a> {
+		self.lexemes.OPERATORS = ops.iter().cloned().filter(|op| op.chars().count() > 1).collect();
+		self.lexemes.OPERATORS.sort_by_key(|op| Reverse(op.chars().count()));
+		self
+	}
+
+	// Comments are recognized wherever a token may start, before any other
+	// class, so e.g. "--" as a line comment takes precedence over '-'.
+	pub fn line_comments(mut self, openers: &[&'a str]) -> LexemesBuilder<'a> {
+		self.lexemes.LINE_COMMENTS = openers.to_vec();
+		self
+	}
+
+	pub fn block_comments(mut self, delims: &[(&'a str, &'a str)]) -> LexemesBuilder<'a> {
+		self.lexemes.BLOCK_COMMENTS = delims.to_vec();
+		self
+	}
+
+	pub fn nest_comments(mut self, nest: bool) -> LexemesBuilder<'a> {
+		self.lexemes.NEST_COMMENTS = nest;
+		self
+	}
+
+	// Emit comments as Token::COMMENT instead of dropping them.
+	pub fn keep_comments(mut self, keep: bool) -> LexemesBuilder<'a> {
+		self.lexemes.KEEP_COMMENTS = keep;
+		self
+	}
+
+	pub fn build(self) -> Lexemes<'a> {
+		self.lexemes
+	}
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Position {
+	pub offset: usize,
+	pub line: usize,
+	pub column: usize,
+}
+
+impl Position {
+	pub fn start() -> Position {
+		Position { offset: 0, line: 1, column: 1 }
+	}
+
+	fn advance(self, c: char) -> Position {
+		if c == '\n' {
+			Position { offset: self.offset + c.len_utf8(), line: self.line + 1, column: 1 }
+		} else {
+			Position { offset: self.offset + c.len_utf8(), line: self.line, column: self.column + 1 }
+		}
+	}
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Span {
+	pub start: Position,
+	pub end: Position,
+}
+
 pub struct Tokenizer<'a, T: Iterator<Item=char>> {
 	reader: T,
-	pushback: Option<char>,
+	lookahead: VecDeque<char>,
 	lexemes: Lexemes<'a>,
+	pos: Position,
+	prev: Position,
+	token_start: Position,
+	recover: bool,
+	errors: Vec<LexError>,
+	failed: bool,
+	raw: Option<String>,
+	trivia_end: usize,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LexErrorKind {
+	UnterminatedString,
+	UnterminatedComment,
+	BadEscape(String),
+	UnexpectedChar(char),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LexError {
+	pub kind: LexErrorKind,
+	pub pos: Position,
+}
+
+impl fmt::Display for LexErrorKind {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match *self {
+			LexErrorKind::UnterminatedString => write!(f, "Unexpected EOF in string"),
+			LexErrorKind::UnterminatedComment => write!(f, "Unexpected EOF in comment"),
+			LexErrorKind::BadEscape(ref digits) => write!(f, "Bad escape constant {:?}", digits),
+			LexErrorKind::UnexpectedChar(c) => write!(f, "Not sure what to do with {:?}", c),
+		}
+	}
+}
+
+impl fmt::Display for LexError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "{}:{}: {}", self.pos.line, self.pos.column, self.kind)
+	}
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NumKind {
+	Int(u32),
+	Float,
+}
+
+#[derive(Debug, Clone, PartialEq)]
 pub enum Token {
 	EOF,
 	STRING(String),
 	OPER(char),
-	NUM(String),
+	OPERATOR(String),
+	// Text (radix prefix kept, separators dropped), kind, and suffix
+	NUM(String, NumKind, String),
 	IDENT(String),
+	COMMENT(String),
+	Error(LexError),
+}
+
+// Decodes UTF-8 from any BufRead as a char iterator. Malformed input is
+// replaced with U+FFFD and its byte offset recorded rather than aborting; an
+// I/O error ends the stream and is kept for inspection.
+pub struct CharReader<R: BufRead> {
+	reader: R,
+	bytes: Vec<u8>,
+	chars: VecDeque<char>,
+	offset: usize,
+	invalid: Vec<usize>,
+	io_error: Option<io::Error>,
+	done: bool,
+}
+
+impl<R: BufRead> CharReader<R> {
+	pub fn new(reader: R) -> CharReader<R> {
+		CharReader {
+			reader,
+			bytes: Vec::new(),
+			chars: VecDeque::new(),
+			offset: 0,
+			invalid: Vec::new(),
+			io_error: None,
+			done: false,
+		}
+	}
+
+	// Byte offsets of each malformed sequence that was replaced.
+	pub fn invalid_offsets(&self) -> &[usize] {
+		&self.invalid
+	}
+
+	pub fn io_error(&self) -> Option<&io::Error> {
+		self.io_error.as_ref()
+	}
+
+	fn decode(&mut self) {
+		loop {
+			let (valid, bad) = match str::from_utf8(&self.bytes) {
+				Ok(text) => {
+					self.chars.extend(text.chars());
+					self.offset += self.bytes.len();
+					self.bytes.clear();
+					return;
+				},
+				Err(e) => (e.valid_up_to(), e.error_len()),
+			};
+			self.chars.extend(str::from_utf8(&self.bytes[..valid]).unwrap().chars());
+			self.offset += valid;
+			match bad {
+				Some(len) => {
+					self.chars.push_back(char::REPLACEMENT_CHARACTER);
+					self.invalid.push(self.offset);
+					self.offset += len;
+					self.bytes.drain(..valid + len);
+				},
+				None => {
+					self.bytes.drain(..valid);
+					return;
+				},
+			}
+		}
+	}
+
+	fn fill(&mut self) {
+		while self.chars.is_empty() && !self.done {
+			let len = match self.reader.fill_buf() {
+				Ok(buf) => {
+					self.bytes.extend_from_slice(buf);
+					buf.len()
+				},
+				Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+				Err(e) => {
+					self.io_error = Some(e);
+					0
+				},
+			};
+			self.reader.consume(len);
+			if len == 0 {
+				self.done = true;
+				if !self.bytes.is_empty() {
+					self.chars.push_back(char::REPLACEMENT_CHARACTER);
+					self.invalid.push(self.offset);
+					self.bytes.clear();
+				}
+			} else {
+				self.decode();
+			}
+		}
+	}
+}
+
+impl<R: BufRead> Iterator for CharReader<R> {
+	type Item = char;
+
+	fn next(&mut self) -> Option<char> {
+		self.fill();
+		self.chars.pop_front()
+	}
 }
 
 fn char_in(s: &str, c: char) -> bool {
@@ -64,155 +433,423 @@ fn char_in(s: &str, c: char) -> bool {
 
 impl<'a, T: Iterator<Item=char>> Tokenizer<'a, T> {
 	pub fn new(reader: T) -> Tokenizer<'a, T> {
+		Tokenizer::with_lexemes(reader, Lexemes::default())
+	}
+
+	pub fn with_lexemes(reader: T, lexemes: Lexemes<'a>) -> Tokenizer<'a, T> {
 		Tokenizer {
 			reader: reader,
-			pushback: None,
-			lexemes: Lexemes::default(),
+			lookahead: VecDeque::new(),
+			lexemes: lexemes,
+			pos: Position::start(),
+			prev: Position::start(),
+			token_start: Position::start(),
+			recover: false,
+			errors: Vec::new(),
+			failed: false,
+			raw: None,
+			trivia_end: 0,
+		}
+	}
+
+	pub fn reader(&self) -> &T {
+		&self.reader
+	}
+
+	pub fn stream(self) -> TokenStream<'a, T> {
+		TokenStream { tokenizer: self, ahead: VecDeque::new() }
+	}
+
+	pub fn position(&self) -> Position {
+		self.pos
+	}
+
+	// Only the most recently read character may be pushed back; use peekchar
+	// to look further ahead.
+	fn push_back(&mut self, c: char) {
+		self.lookahead.push_front(c);
+		self.pos = self.prev;
+		if let Some(ref mut raw) = self.raw {
+			raw.pop();
 		}
 	}
 
-	fn push_back(&mut self, c: char) -> bool {
-		match (*self).pushback {
-			None => {
-				(*self).pushback = Some(c);
-				true
-			},
-			Some(_) => {
-				false
+	fn peekchar(&mut self, n: usize) -> Option<char> {
+		while self.lookahead.len() <= n {
+			match self.reader.next() {
+				Some(c) => self.lookahead.push_back(c),
+				None => return None,
 			}
 		}
+		Some(self.lookahead[n])
 	}
 
 	fn nextchar(&mut self) -> Option<char> {
-		match (*self).pushback {
-			Some(c) => {
-				(*self).pushback = None;
-				Some(c)
-			},
+		let c = match self.lookahead.pop_front() {
+			Some(c) => Some(c),
 			None => self.reader.next(),
+		};
+		if let Some(c) = c {
+			self.prev = self.pos;
+			self.pos = self.pos.advance(c);
+			if let Some(ref mut raw) = self.raw {
+				raw.push(c);
+			}
 		}
+		c
+	}
+
+	pub fn next_spanned(&mut self) -> (Token, Span) {
+		let tok = self.nexttoken();
+		(tok, Span { start: self.token_start, end: self.pos })
+	}
+
+	pub fn spanned(self) -> Spanned<'a, T> {
+		Spanned { tokenizer: self }
+	}
+
+	pub fn errors(&self) -> &[LexError] {
+		&self.errors
+	}
+
+	pub fn set_recovery(&mut self, recover: bool) {
+		self.recover = recover;
+	}
+
+	pub fn with_recovery(mut self) -> Tokenizer<'a, T> {
+		self.recover = true;
+		self
+	}
+
+	// Lossless mode turns on recovery, so input that fails to lex ends up as
+	// trivia rather than cutting the stream short; check errors() afterwards.
+	pub fn lossless(mut self) -> Lossless<'a, T> {
+		self.raw = Some(String::new());
+		self.recover = true;
+		Lossless { tokenizer: self, pending: None, done: false }
+	}
+
+	// In recovery mode an error is recorded (see errors()) and lexing carries
+	// on past the offending input; otherwise it's returned and ends the stream.
+	fn error(&mut self, kind: LexErrorKind, pos: Position) -> Result<(), LexError> {
+		let err = LexError { kind, pos };
+		if self.recover {
+			self.errors.push(err);
+			Ok(())
+		} else {
+			Err(err)
+		}
+	}
+
+	pub fn try_nexttoken(&mut self) -> Result<Token, LexError> {
+		if self.failed {
+			return Ok(Token::EOF);
+		}
+		let result = self.lex();
+		if result.is_err() {
+			self.failed = true;
+		}
+		result
 	}
 
 	pub fn nexttoken(&mut self) -> Token {
-		let mut c = self.nextchar();
-		if c == None {
-			return Token::EOF;
+		match self.try_nexttoken() {
+			Ok(tok) => tok,
+			Err(err) => Token::Error(err),
 		}
-		while char_in(self.lexemes.WS, c.unwrap()) {
-			c = self.nextchar();
-			if c == None {
-				return Token::EOF;
+	}
+
+	fn escape_digits(&mut self, digits: &str, max: usize) -> String {
+		let mut val = String::new();
+		while val.len() < max {
+			match self.peekchar(0) {
+				Some(c) if char_in(digits, c) => {
+					val.push(c);
+					self.nextchar();
+				},
+				_ => break,
 			}
 		}
-		let cc = c.unwrap();
-		if char_in(self.lexemes.STR_GRP, cc) {
-			let termin = cc;
-			let mut value = String::new();
-			loop {
-				let i = self.nextchar();
-				if i == None {
-					panic!("Unexpected EOF in string");
+		val
+	}
+
+	fn digit_run(&mut self, class: &str, text: &mut String) {
+		loop {
+			match self.peekchar(0) {
+				Some(c) if char_in(class, c) => {
+					text.push(c);
+					self.nextchar();
+				},
+				Some(c) if Some(c) == self.lexemes.DIGIT_SEP && self.peekchar(1).is_some_and(|d| char_in(class, d)) => {
+					self.nextchar();
+				},
+				_ => return,
+			}
+		}
+	}
+
+	fn number(&mut self, first: char) -> Token {
+		let mut text = String::new();
+		text.push(first);
+		let mut kind = NumKind::Int(10);
+		if first == '0' && self.lexemes.RADIX_PREFIXES {
+			let radix = match self.peekchar(0) {
+				Some('x') | Some('X') => Some((16, self.lexemes.HEX_DIGIT)),
+				Some('o') | Some('O') => Some((8, self.lexemes.OCT_DIGIT)),
+				Some('b') | Some('B') => Some((2, "01")),
+				_ => None,
+			};
+			if let Some((radix, class)) = radix {
+				if self.peekchar(1).is_some_and(|c| char_in(class, c)) {
+					text.push(self.nextchar().unwrap());
+					self.digit_run(class, &mut text);
+					kind = NumKind::Int(radix);
 				}
-				let ic = i.unwrap();
-				if ic == termin {
-					return Token::STRING(value);
+			}
+		}
+		if kind == NumKind::Int(10) {
+			let digit = self.lexemes.DIGIT;
+			self.digit_run(digit, &mut text);
+			if self.lexemes.FLOATS {
+				if self.peekchar(0) == Some('.') && self.peekchar(1).is_some_and(|c| char_in(digit, c)) {
+					text.push(self.nextchar().unwrap());
+					self.digit_run(digit, &mut text);
+					kind = NumKind::Float;
 				}
-				if ic == self.lexemes.ESCAPE {
-					let ty = self.nextchar().expect("Unexpected EOF in string escape");
-					if ty == self.lexemes.ESC_HEX {
-						let mut hval = String::new();
-						loop {
-							let hc = self.nextchar().expect("Unexpected EOF in hex const");
-							if char_in(self.lexemes.HEX_DIGIT, hc) {
-								hval.push(hc);
-							} else {
-								self.push_back(hc);
-								break;
-							}
+				if let Some(e) = self.peekchar(0).filter(|&e| e == 'e' || e == 'E') {
+					let signed = self.peekchar(1).is_some_and(|c| c == '+' || c == '-');
+					let at = if signed { 2 } else { 1 };
+					if self.peekchar(at).is_some_and(|c| char_in(digit, c)) {
+						self.nextchar();
+						text.push(e);
+						if signed {
+							text.push(self.nextchar().unwrap());
 						}
-						value.push(u8::from_str_radix(&hval, 16).expect("Bad hex escape constant") as char);
-						continue
+						self.digit_run(digit, &mut text);
+						kind = NumKind::Float;
 					}
-					if ty == self.lexemes.ESC_OCT {
-						let mut oval = String::new();
-						loop {
-							let oc = self.nextchar().expect("Unexpected EOF in oct const");
-							if char_in(self.lexemes.OCT_DIGIT, oc) {
-								oval.push(oc);
-							} else {
-								self.push_back(oc);
-								break;
-							}
-						}
-						value.push(u8::from_str_radix(&oval, 8).expect("Bad oct escape constant") as char);
-						continue
-					}
-					value.push(*self.lexemes.ESCAPES.get(&ty).unwrap_or(&ty));
-					continue
 				}
-				value.push(ic);
 			}
 		}
-		if char_in(self.lexemes.PUNCT, cc) {
-			if cc == '/' {
-				let i = self.nextchar();
-				if i == None {
-					return Token::OPER(cc);
-				}
-				let ic = i.unwrap();
-				if ic == '*' {
-					loop {
-						let j = self.nextchar();
-						match j {
-							None => return Token::EOF,
-							Some('*') => {
-								let k = self.nextchar();
-								match k {
-									None => return Token::EOF,
-									Some('/') => return self.nexttoken(),
-									Some(_) => continue,
-								}
-							},
-							Some(_) => continue,
-						}
-					}
+		let mut suffix = String::new();
+		'suffixes: for idx in 0..self.lexemes.NUM_SUFFIXES.len() {
+			let suf = self.lexemes.NUM_SUFFIXES[idx];
+			let len = suf.chars().count();
+			for (i, sc) in suf.chars().enumerate() {
+				if self.peekchar(i) != Some(sc) {
+					continue 'suffixes;
 				}
 			}
-			return Token::OPER(cc);
+			if self.peekchar(len).is_some_and(|c| self.lexemes.is_ident(c)) {
+				continue;
+			}
+			self.take(suf, &mut suffix);
+			break;
 		}
-		if char_in(self.lexemes.DIGIT, cc) {
-			let mut num = String::new();
-			num.push(cc);
-			loop {
-				let i = self.nextchar();
-				if i == None {
-					return Token::NUM(num);
+		Token::NUM(text, kind, suffix)
+	}
+
+	fn operator(&mut self, first: char) -> Option<&'a str> {
+		'ops: for idx in 0..self.lexemes.OPERATORS.len() {
+			let op = self.lexemes.OPERATORS[idx];
+			let mut chars = op.chars();
+			if chars.next() != Some(first) {
+				continue;
+			}
+			for (i, oc) in chars.enumerate() {
+				if self.peekchar(i) != Some(oc) {
+					continue 'ops;
 				}
-				let ic = i.unwrap();
-				if !char_in(self.lexemes.DIGIT, ic) {
-					self.push_back(ic);
-					return Token::NUM(num);
+			}
+			for _ in 1..op.chars().count() {
+				self.nextchar();
+			}
+			return Some(op);
+		}
+		None
+	}
+
+	fn starts_with(&mut self, first: char, s: &str) -> bool {
+		let mut chars = s.chars();
+		if chars.next() != Some(first) {
+			return false;
+		}
+		for (i, sc) in chars.enumerate() {
+			if self.peekchar(i) != Some(sc) {
+				return false;
+			}
+		}
+		true
+	}
+
+	fn take(&mut self, s: &str, text: &mut String) {
+		for _ in 0..s.chars().count() {
+			if let Some(c) = self.nextchar() {
+				text.push(c);
+			}
+		}
+	}
+
+	// If `first` (already read) opens a comment, consumes the rest of it and
+	// returns its full text, delimiters included.
+	fn comment(&mut self, first: char) -> Result<Option<String>, LexError> {
+		let mut text = String::new();
+		text.push(first);
+		for idx in 0..self.lexemes.LINE_COMMENTS.len() {
+			let open = self.lexemes.LINE_COMMENTS[idx];
+			if self.starts_with(first, open) {
+				self.take(&open[first.len_utf8()..], &mut text);
+				while let Some(c) = self.peekchar(0) {
+					if c == '\n' { break; }
+					text.push(c);
+					self.nextchar();
 				}
-				num.push(ic);
+				return Ok(Some(text));
 			}
 		}
-		if char_in(self.lexemes.IDENT_START, cc) {
-			let mut ident = String::new();
-			ident.push(cc);
+		for idx in 0..self.lexemes.BLOCK_COMMENTS.len() {
+			let (open, close) = self.lexemes.BLOCK_COMMENTS[idx];
+			if !self.starts_with(first, open) {
+				continue;
+			}
+			let start = self.token_start;
+			self.take(&open[first.len_utf8()..], &mut text);
+			let mut depth = 1;
 			loop {
-				let i = self.nextchar();
-				if i == None {
-					return Token::IDENT(ident);
+				let c = match self.nextchar() {
+					None => {
+						self.error(LexErrorKind::UnterminatedComment, start)?;
+						return Ok(Some(text));
+					},
+					Some(c) => c,
+				};
+				if self.starts_with(c, close) {
+					text.push(c);
+					self.take(&close[c.len_utf8()..], &mut text);
+					depth -= 1;
+					if depth == 0 {
+						return Ok(Some(text));
+					}
+				} else if self.lexemes.NEST_COMMENTS && self.starts_with(c, open) {
+					text.push(c);
+					self.take(&open[c.len_utf8()..], &mut text);
+					depth += 1;
+				} else {
+					text.push(c);
+				}
+			}
+		}
+		Ok(None)
+	}
+
+	fn lex(&mut self) -> Result<Token, LexError> {
+		loop {
+			self.token_start = self.pos;
+			self.trivia_end = self.raw.as_ref().map_or(0, |raw| raw.len());
+			let cc = match self.nextchar() {
+				None => return Ok(Token::EOF),
+				Some(c) => c,
+			};
+			if char_in(self.lexemes.WS, cc) {
+				continue;
+			}
+			if let Some(text) = self.comment(cc)? {
+				if self.lexemes.KEEP_COMMENTS {
+					return Ok(Token::COMMENT(text));
 				}
-				let ic = i.unwrap();
-				if !char_in(self.lexemes.IDENT, ic) {
-					self.push_back(ic);
-					return Token::IDENT(ident);
+				continue;
+			}
+			if char_in(self.lexemes.STR_GRP, cc) {
+				let termin = cc;
+				let mut value = String::new();
+				loop {
+					let i = self.nextchar();
+					if i == None {
+						let start = self.token_start;
+						self.error(LexErrorKind::UnterminatedString, start)?;
+						return Ok(Token::STRING(value));
+					}
+					let ic = i.unwrap();
+					if ic == termin {
+						return Ok(Token::STRING(value));
+					}
+					if ic == self.lexemes.ESCAPE {
+						let esc_pos = self.prev;
+						let ty = match self.nextchar() {
+							Some(ty) => ty,
+							None => {
+								self.error(LexErrorKind::UnterminatedString, esc_pos)?;
+								return Ok(Token::STRING(value));
+							},
+						};
+						if ty == self.lexemes.ESC_UNI && self.peekchar(0) == Some('{') {
+							self.nextchar();
+							let val = self.escape_digits(self.lexemes.HEX_DIGIT, 6);
+							let closed = self.peekchar(0) == Some('}');
+							if closed {
+								self.nextchar();
+							}
+							match u32::from_str_radix(&val, 16).ok().and_then(char::from_u32) {
+								Some(uc) if closed => value.push(uc),
+								_ => self.error(LexErrorKind::BadEscape(val), esc_pos)?,
+							}
+							continue
+						}
+						if ty == self.lexemes.ESC_HEX || ty == self.lexemes.ESC_OCT {
+							let (radix, digits, max) = if ty == self.lexemes.ESC_HEX { (16, self.lexemes.HEX_DIGIT, 2) } else { (8, self.lexemes.OCT_DIGIT, 3) };
+							let val = self.escape_digits(digits, max);
+							match u32::from_str_radix(&val, radix).ok().and_then(char::from_u32) {
+								Some(ec) => value.push(ec),
+								None => self.error(LexErrorKind::BadEscape(val), esc_pos)?,
+							}
+							continue
+						}
+						value.push(*self.lexemes.ESCAPES.get(&ty).unwrap_or(&ty));
+						continue
+					}
+					value.push(ic);
+				}
+			}
+			if char_in(self.lexemes.PUNCT, cc) {
+				if let Some(op) = self.operator(cc) {
+					return Ok(Token::OPERATOR(op.to_string()));
 				}
-				ident.push(ic);
+				return Ok(Token::OPER(cc));
+			}
+			if char_in(self.lexemes.DIGIT, cc) {
+				return Ok(self.number(cc));
 			}
+			if self.lexemes.is_ident_start(cc) {
+				let mut ident = String::new();
+				ident.push(cc);
+				loop {
+					let i = self.nextchar();
+					if i == None {
+						return Ok(Token::IDENT(ident));
+					}
+					let ic = i.unwrap();
+					if !self.lexemes.is_ident(ic) {
+						self.push_back(ic);
+						return Ok(Token::IDENT(ident));
+					}
+					ident.push(ic);
+				}
+			}
+			let start = self.token_start;
+			self.error(LexErrorKind::UnexpectedChar(cc), start)?;
 		}
-		panic!("Not sure what to do with {:?}", c);
+	}
+}
+
+impl<'a, 's> Tokenizer<'a, str::Chars<'s>> {
+	pub fn from_text(text: &'s str) -> Tokenizer<'a, str::Chars<'s>> {
+		Tokenizer::new(text.chars())
+	}
+}
+
+impl<'a, R: BufRead> Tokenizer<'a, CharReader<R>> {
+	pub fn from_reader(reader: R) -> Tokenizer<'a, CharReader<R>> {
+		Tokenizer::new(CharReader::new(reader))
 	}
 }
 
@@ -226,3 +863,361 @@ impl<'a, T: Iterator<Item=char>> Iterator for Tokenizer<'a, T> {
 		}
 	}
 }
+
+pub struct Spanned<'a, T: Iterator<Item=char>> {
+	tokenizer: Tokenizer<'a, T>,
+}
+
+impl<'a, T: Iterator<Item=char>> Iterator for Spanned<'a, T> {
+	type Item = (Token, Span);
+
+	fn next(&mut self) -> Option<(Token, Span)> {
+		match self.tokenizer.next_spanned() {
+			(Token::EOF, _) => None,
+			x => Some(x),
+		}
+	}
+}
+
+// The source text around and of a token: `leading` is the whitespace,
+// comments and skipped input since the end of the previous token's line,
+// `text` the token's exact spelling and `trailing` whatever follows it up to
+// and including the end of its line. Concatenating all three for every token
+// (EOF included) gives back the input.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Trivia {
+	pub leading: String,
+	pub text: String,
+	pub trailing: String,
+}
+
+// Yields each token with its trivia; the last item is the EOF token, whose
+// leading trivia is everything after the final token's line.
+pub struct Lossless<'a, T: Iterator<Item=char>> {
+	tokenizer: Tokenizer<'a, T>,
+	pending: Option<(Token, Trivia)>,
+	done: bool,
+}
+
+impl<'a, T: Iterator<Item=char>> Lossless<'a, T> {
+	pub fn tokenizer(&self) -> &Tokenizer<'a, T> {
+		&self.tokenizer
+	}
+}
+
+impl<'a, T: Iterator<Item=char>> Iterator for Lossless<'a, T> {
+	type Item = (Token, Trivia);
+
+	fn next(&mut self) -> Option<(Token, Trivia)> {
+		loop {
+			if self.done {
+				return None;
+			}
+			if let Some((Token::EOF, _)) = self.pending {
+				self.done = true;
+				return self.pending.take();
+			}
+			let tok = self.tokenizer.nexttoken();
+			let mut gap = self.tokenizer.raw.replace(String::new()).unwrap_or_default();
+			let text = gap.split_off(self.tokenizer.trivia_end);
+			let split = if self.pending.is_some() { gap.find('\n').map_or(gap.len(), |i| i + 1) } else { 0 };
+			let leading = gap.split_off(split);
+			if let Some((prev, mut trivia)) = self.pending.replace((tok, Trivia { leading, text, trailing: String::new() })) {
+				trivia.trailing = gap;
+				return Some((prev, trivia));
+			}
+		}
+	}
+}
+
+// A token stream with arbitrary lookahead: peek(n) looks n tokens past the
+// next one without consuming anything.
+pub struct TokenStream<'a, T: Iterator<Item=char>> {
+	tokenizer: Tokenizer<'a, T>,
+	ahead: VecDeque<(Token, Span)>,
+}
+
+impl<'a, T: Iterator<Item=char>> TokenStream<'a, T> {
+	pub fn peek_spanned(&mut self, n: usize) -> Option<&(Token, Span)> {
+		while self.ahead.len() <= n {
+			match self.tokenizer.next_spanned() {
+				(Token::EOF, _) => return None,
+				x => self.ahead.push_back(x),
+			}
+		}
+		self.ahead.get(n)
+	}
+
+	pub fn peek(&mut self, n: usize) -> Option<&Token> {
+		self.peek_spanned(n).map(|x| &x.0)
+	}
+
+	pub fn next_spanned(&mut self) -> Option<(Token, Span)> {
+		self.peek_spanned(0);
+		self.ahead.pop_front()
+	}
+
+	pub fn tokenizer(&self) -> &Tokenizer<'a, T> {
+		&self.tokenizer
+	}
+}
+
+impl<'a, T: Iterator<Item=char>> Iterator for TokenStream<'a, T> {
+	type Item = Token;
+
+	fn next(&mut self) -> Option<Token> {
+		self.next_spanned().map(|x| x.0)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn tokens(src: &str) -> Vec<Token> {
+		Tokenizer::new(src.chars()).collect()
+	}
+
+	#[test]
+	fn errors_end_the_stream() {
+		let toks = tokens("a \"unterminated");
+		assert_eq!(toks.len(), 2);
+		match toks[1] {
+			Token::Error(LexError { kind: LexErrorKind::UnterminatedString, pos }) => assert_eq!(pos.column, 3),
+			ref t => panic!("{:?}", t),
+		}
+
+		let mut t = Tokenizer::new("a /* open".chars());
+		assert_eq!(t.try_nexttoken(), Ok(Token::IDENT("a".to_string())));
+		assert_eq!(t.try_nexttoken().unwrap_err().kind, LexErrorKind::UnterminatedComment);
+		assert_eq!(t.try_nexttoken(), Ok(Token::EOF));
+	}
+
+	#[test]
+	fn recovery_skips_bad_input() {
+		let mut t = Tokenizer::new("a \u{7f} b\n'\\xzz' c".chars()).with_recovery();
+		let toks: Vec<Token> = t.by_ref().collect();
+		assert_eq!(toks, vec![
+			Token::IDENT("a".to_string()),
+			Token::IDENT("b".to_string()),
+			Token::STRING("zz".to_string()),
+			Token::IDENT("c".to_string()),
+		]);
+		let errs = t.errors();
+		assert_eq!(errs.len(), 2);
+		assert_eq!(errs[0].kind, LexErrorKind::UnexpectedChar('\u{7f}'));
+		assert_eq!((errs[0].pos.line, errs[0].pos.column), (1, 3));
+		assert_eq!(errs[1].kind, LexErrorKind::BadEscape(String::new()));
+		assert_eq!((errs[1].pos.line, errs[1].pos.column), (2, 2));
+	}
+
+	#[test]
+	fn custom_lexemes() {
+		let lexemes = Lexemes::builder()
+			.punctuation("(){};=")
+			.string_delimiters("`")
+			.ident_start("$abcdefghijklmnopqrstuvwxyz")
+			.ident_continue("$abcdefghijklmnopqrstuvwxyz0123456789")
+			.escape('e', '\u{1b}')
+			.build();
+		let toks: Vec<Token> = Tokenizer::with_lexemes("$x1 = `it's\\e`;".chars(), lexemes).collect();
+		assert_eq!(toks, vec![
+			Token::IDENT("$x1".to_string()),
+			Token::OPER('='),
+			Token::STRING("it's\u{1b}".to_string()),
+			Token::OPER(';'),
+		]);
+	}
+
+	#[test]
+	fn longest_match_operators() {
+		let lexemes = Lexemes::builder().operators(&["-", "->", "->>", "::", "==", "<="]).build();
+		let toks: Vec<Token> = Tokenizer::with_lexemes("a->b->>c-d::e<=f==<-/**/>".chars(), lexemes).collect();
+		assert_eq!(toks, vec![
+			Token::IDENT("a".to_string()),
+			Token::OPERATOR("->".to_string()),
+			Token::IDENT("b".to_string()),
+			Token::OPERATOR("->>".to_string()),
+			Token::IDENT("c".to_string()),
+			Token::OPER('-'),
+			Token::IDENT("d".to_string()),
+			Token::OPERATOR("::".to_string()),
+			Token::IDENT("e".to_string()),
+			Token::OPERATOR("<=".to_string()),
+			Token::IDENT("f".to_string()),
+			Token::OPERATOR("==".to_string()),
+			Token::OPER('<'),
+			Token::OPER('-'),
+			Token::OPER('>'),
+		]);
+	}
+
+	#[test]
+	fn configurable_comments() {
+		let lexemes = Lexemes::builder()
+			.line_comments(&["//", "#", "--"])
+			.block_comments(&[("/*", "*/"), ("{-", "-}")])
+			.nest_comments(true)
+			.keep_comments(true)
+			.build();
+		let src = "a # one\n-- two\nb - c // three\n/* x /* y */ z */{- -}d";
+		let toks: Vec<Token> = Tokenizer::with_lexemes(src.chars(), lexemes).collect();
+		assert_eq!(toks, vec![
+			Token::IDENT("a".to_string()),
+			Token::COMMENT("# one".to_string()),
+			Token::COMMENT("-- two".to_string()),
+			Token::IDENT("b".to_string()),
+			Token::OPER('-'),
+			Token::IDENT("c".to_string()),
+			Token::COMMENT("// three".to_string()),
+			Token::COMMENT("/* x /* y */ z */".to_string()),
+			Token::COMMENT("{- -}".to_string()),
+			Token::IDENT("d".to_string()),
+		]);
+
+		let lexemes = Lexemes::builder().line_comments(&["#"]).build();
+		let toks: Vec<Token> = Tokenizer::with_lexemes("/* x /* y */ z */ # w".chars(), lexemes).collect();
+		assert_eq!(toks, vec![Token::IDENT("z".to_string()), Token::OPER('*'), Token::OPER('/')]);
+	}
+
+	#[test]
+	fn unicode_escapes() {
+		let toks = tokens("'\\u{1F600}\\x4142\\0101\\u{e9}\\u'");
+		assert_eq!(toks, vec![Token::STRING("\u{1F600}A42A\u{e9}u".to_string())]);
+		for bad in &["'\\u{110000}'", "'\\u{D800}'", "'\\u{41'", "'\\u{}'", "'\\u{1234567}'"] {
+			let mut t = Tokenizer::new(bad.chars());
+			match t.nexttoken() {
+				Token::Error(LexError { kind: LexErrorKind::BadEscape(_), .. }) => (),
+				tok => panic!("{}: {:?}", bad, tok),
+			}
+		}
+	}
+
+	#[test]
+	fn unicode_identifiers() {
+		assert_eq!(Tokenizer::new("\u{e9}".chars()).nexttoken(), Token::Error(LexError {
+			kind: LexErrorKind::UnexpectedChar('\u{e9}'),
+			pos: Position::start(),
+		}));
+		let lexemes = Lexemes::builder().unicode_idents(true).build();
+		let toks: Vec<Token> = Tokenizer::with_lexemes("gr\u{f6}\u{df}e = \u{540d}\u{524d}_2".chars(), lexemes).collect();
+		assert_eq!(toks, vec![
+			Token::IDENT("gr\u{f6}\u{df}e".to_string()),
+			Token::OPER('='),
+			Token::IDENT("\u{540d}\u{524d}_2".to_string()),
+		]);
+	}
+
+	fn num(text: &str, kind: NumKind, suffix: &str) -> Token {
+		Token::NUM(text.to_string(), kind, suffix.to_string())
+	}
+
+	#[test]
+	fn numeric_literals() {
+		assert_eq!(tokens("3.14 0x1F 0o17 0b1010 1_000_000 2.5e-3 1E9 007"), vec![
+			num("3.14", NumKind::Float, ""),
+			num("0x1F", NumKind::Int(16), ""),
+			num("0o17", NumKind::Int(8), ""),
+			num("0b1010", NumKind::Int(2), ""),
+			num("1000000", NumKind::Int(10), ""),
+			num("2.5e-3", NumKind::Float, ""),
+			num("1E9", NumKind::Float, ""),
+			num("007", NumKind::Int(10), ""),
+		]);
+		assert_eq!(tokens("1..2 3.x 0xg 1e 1_"), vec![
+			num("1", NumKind::Int(10), ""),
+			Token::OPER('.'),
+			Token::OPER('.'),
+			num("2", NumKind::Int(10), ""),
+			num("3", NumKind::Int(10), ""),
+			Token::OPER('.'),
+			Token::IDENT("x".to_string()),
+			num("0", NumKind::Int(10), ""),
+			Token::IDENT("xg".to_string()),
+			num("1", NumKind::Int(10), ""),
+			Token::IDENT("e".to_string()),
+			num("1", NumKind::Int(10), ""),
+			Token::IDENT("_".to_string()),
+		]);
+
+		let lexemes = Lexemes::builder().number_suffixes(&["u", "u32", "f64", "L"]).build();
+		let toks: Vec<Token> = Tokenizer::with_lexemes("10u32 7u 2.0f64 0xFFL 5uint".chars(), lexemes).collect();
+		assert_eq!(toks, vec![
+			num("10", NumKind::Int(10), "u32"),
+			num("7", NumKind::Int(10), "u"),
+			num("2.0", NumKind::Float, "f64"),
+			num("0xFF", NumKind::Int(16), "L"),
+			num("5", NumKind::Int(10), ""),
+			Token::IDENT("uint".to_string()),
+		]);
+	}
+
+	#[test]
+	fn reader_decodes_utf8() {
+		use std::io::BufReader;
+
+		let bytes = "a \u{e9}t\u{e9} '\u{1F600}'".as_bytes();
+		let toks: Vec<Token> = Tokenizer::from_reader(BufReader::with_capacity(1, bytes)).collect();
+		assert_eq!(toks, vec![
+			Token::IDENT("a".to_string()),
+			Token::Error(LexError { kind: LexErrorKind::UnexpectedChar('\u{e9}'), pos: Position { offset: 2, line: 1, column: 3 } }),
+		]);
+		let toks: Vec<Token> = Tokenizer::from_reader(BufReader::with_capacity(1, bytes)).with_recovery().collect();
+		assert_eq!(toks.last(), Some(&Token::STRING("\u{1F600}".to_string())));
+
+		let mut t = Tokenizer::from_reader(BufReader::with_capacity(2, &b"ab\xffcd '\xe2\x82'"[..])).with_recovery();
+		let toks: Vec<Token> = t.by_ref().collect();
+		assert_eq!(toks, vec![
+			Token::IDENT("ab".to_string()),
+			Token::IDENT("cd".to_string()),
+			Token::STRING("\u{fffd}".to_string()),
+		]);
+		assert_eq!(t.reader().invalid_offsets(), &[2, 7]);
+		assert!(t.reader().io_error().is_none());
+
+		let mut r = CharReader::new(&b"\xe2\x82"[..]);
+		assert_eq!(r.next(), Some('\u{fffd}'));
+		assert_eq!(r.next(), None);
+	}
+
+	#[test]
+	fn stream_lookahead() {
+		let mut s = Tokenizer::from_text("a -> b").stream();
+		assert_eq!(s.peek(3), Some(&Token::IDENT("b".to_string())));
+		assert_eq!(s.peek(1), Some(&Token::OPER('-')));
+		assert_eq!(s.peek(4), None);
+		assert_eq!(s.next(), Some(Token::IDENT("a".to_string())));
+		let (tok, span) = s.next_spanned().unwrap();
+		assert_eq!((tok, span.start.column), (Token::OPER('-'), 3));
+		assert_eq!(s.peek_spanned(1).map(|x| x.1.start.column), Some(6));
+		assert_eq!(s.collect::<Vec<Token>>(), vec![Token::OPER('>'), Token::IDENT("b".to_string())]);
+	}
+
+	#[test]
+	fn slash_is_not_swallowed() {
+		assert_eq!(tokens("a/b */"), vec![
+			Token::IDENT("a".to_string()),
+			Token::OPER('/'),
+			Token::IDENT("b".to_string()),
+			Token::OPER('*'),
+			Token::OPER('/'),
+		]);
+		assert_eq!(tokens("/** x **/y"), vec![Token::IDENT("y".to_string())]);
+	}
+
+	#[test]
+	fn lossless_trivia() {
+		let src = "  a = 'x\\'' /* c */\n\t1_000 \u{7f}b\n\n";
+		let items: Vec<(Token, Trivia)> = Tokenizer::new(src.chars()).lossless().collect();
+		let rebuilt: String = items.iter().map(|(_, t)| format!("{}{}{}", t.leading, t.text, t.trailing)).collect();
+		assert_eq!(rebuilt, src);
+		let texts: Vec<&str> = items.iter().map(|(_, t)| t.text.as_str()).collect();
+		assert_eq!(texts, vec!["a", "=", "'x\\''", "1_000", "b", ""]);
+		assert_eq!(items[0].1.leading, "  ");
+		assert_eq!(items[2].1.trailing, " /* c */\n");
+		assert_eq!(items[3].1.leading, "\t");
+		assert_eq!(items[3].1.trailing, " \u{7f}");
+		assert_eq!(items[4].1.trailing, "\n");
+		assert_eq!(items[5], (Token::EOF, Trivia { leading: "\n".to_string(), ..Trivia::default() }));
+	}
+}
@@ -1,22 +1,421 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+
 use ctok::*;
 use ns::*;
+use track::*;
 use super::*;
 
-pub fn to_tree<T: Iterator<Item=char>>(t: Tokenizer<T>, ns: &mut Namespace) -> Node {
-	let mut tree = Group(ns.to_int("document"), Vec::new());
-	for tok in t {
-		match tree {
-			Group(_, ref mut children) => {
-				children.push(match tok {
-					Token::STRING(s) => Group(ns.to_int("string"), vec![Atom(ns.to_int(&s))]),
-					Token::OPER(c) => Group(ns.to_int("oper"), vec![Atom(ns.to_int(&c.to_string()))]),
-					Token::NUM(s) => Group(ns.to_int("num"), vec![Atom(ns.to_int(&s))]),
-					Token::IDENT(s) => Group(ns.to_int("ident"), vec![Atom(ns.to_int(&s))]),
-					_ => unreachable!(),
-				});
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum NodeKind {
+	Document,
+	String,
+	Oper,
+	Int,
+	Float,
+	Ident,
+	Keyword,
+	Comment,
+	Error,
+	Paren,
+	Bracket,
+	Brace,
+}
+
+// How tokens become nodes: the group name for each kind of node, which token
+// kinds are emitted as a bare Atom instead of a group, and which identifiers
+// are keywords. The default reproduces the original names, so integers and
+// floats are both `num`, and has no keywords.
+#[derive(Debug, Clone)]
+pub struct TreeConfig<'a> {
+	names: HashMap<NodeKind, &'a str>,
+	bare: HashSet<NodeKind>,
+	keywords: HashSet<&'a str>,
+}
+
+impl<'a> Default for TreeConfig<'a> {
+	fn default() -> TreeConfig<'a> {
+		let names = [
+			(NodeKind::Document, "document"),
+			(NodeKind::String, "string"),
+			(NodeKind::Oper, "oper"),
+			(NodeKind::Int, "num"),
+			(NodeKind::Float, "num"),
+			(NodeKind::Ident, "ident"),
+			(NodeKind::Keyword, "keyword"),
+			(NodeKind::Comment, "comment"),
+			(NodeKind::Error, "error"),
+			(NodeKind::Paren, "paren"),
+			(NodeKind::Bracket, "bracket"),
+			(NodeKind::Brace, "brace"),
+		];
+		TreeConfig { names: names.iter().cloned().collect(), bare: HashSet::new(), keywords: HashSet::new() }
+	}
+}
+
+pub struct TreeConfigBuilder<'a> {
+	config: TreeConfig<'a>,
+}
+
+impl<'a> TreeConfigBuilder<'a> {
+	pub fn name(mut self, kind: NodeKind, name: &'a str) -> TreeConfigBuilder<'a> {
+		self.config.names.insert(kind, name);
+		self
+	}
+
+	// Bare tokens are just their text; a number's suffix is appended to it.
+	pub fn bare(mut self, kind: NodeKind, bare: bool) -> TreeConfigBuilder<'a> {
+		if bare {
+			self.config.bare.insert(kind);
+		} else {
+			self.config.bare.remove(&kind);
+		}
+		self
+	}
+
+	pub fn keywords(mut self, words: &[&'a str]) -> TreeConfigBuilder<'a> {
+		self.config.keywords = words.iter().cloned().collect();
+		self
+	}
+
+	pub fn build(self) -> TreeConfig<'a> {
+		self.config
+	}
+}
+
+impl<'a> TreeConfig<'a> {
+	pub fn builder() -> TreeConfigBuilder<'a> {
+		TreeConfigBuilder { config: TreeConfig::default() }
+	}
+
+	pub fn name(&self, kind: NodeKind) -> &'a str {
+		self.names[&kind]
+	}
+
+	fn token_node<I: Interner>(&self, tok: Token, ns: &mut I) -> Node {
+		let (kind, mut text, extra) = match tok {
+			Token::STRING(s) => (NodeKind::String, s, None),
+			Token::OPER(c) => (NodeKind::Oper, c.to_string(), None),
+			Token::OPERATOR(s) => (NodeKind::Oper, s, None),
+			Token::NUM(s, NumKind::Int(_), suffix) => (NodeKind::Int, s, Some(suffix).filter(|x| !x.is_empty())),
+			Token::NUM(s, NumKind::Float, suffix) => (NodeKind::Float, s, Some(suffix).filter(|x| !x.is_empty())),
+			Token::IDENT(s) => (if self.keywords.contains(s.as_str()) { NodeKind::Keyword } else { NodeKind::Ident }, s, None),
+			Token::COMMENT(s) => (NodeKind::Comment, s, None),
+			Token::Error(e) => (NodeKind::Error, e.to_string(), None),
+			Token::EOF => unreachable!(),
+		};
+		if self.bare.contains(&kind) {
+			text.push_str(&extra.unwrap_or_default());
+			return Atom(ns.to_int(&text));
+		}
+		let mut children = vec![Atom(ns.to_int(&text))];
+		if let Some(extra) = extra {
+			children.push(Atom(ns.to_int(&extra)));
+		}
+		Group(ns.to_int(self.name(kind)), children)
+	}
+
+	pub fn tree<T: Iterator<Item=char>, I: Interner>(&self, t: Tokenizer<T>, ns: &mut I) -> Node {
+		let children = t.map(|tok| self.token_node(tok, ns)).collect();
+		Group(ns.to_int(self.name(NodeKind::Document)), children)
+	}
+
+	pub fn spanned<T: Iterator<Item=char>, I: Interner>(&self, t: Tokenizer<T>, ns: &mut I) -> (Node, Annotations<Span>) {
+		let mut children = Vec::new();
+		let mut spans = Annotations::new();
+		for (tok, span) in t.spanned() {
+			let node = self.token_node(tok, ns);
+			spans.insert(vec![children.len()], span);
+			if let Group(..) = node {
+				spans.insert(vec![children.len(), 0], span);
+			}
+			children.push(node);
+		}
+		(Group(ns.to_int(self.name(NodeKind::Document)), children), spans)
+	}
+
+	pub fn lossless<T: Iterator<Item=char>, I: Interner>(&self, t: Tokenizer<T>, ns: &mut I) -> (Node, Annotations<Trivia>) {
+		let mut children = Vec::new();
+		let mut trivia = Annotations::new();
+		for (tok, triv) in t.lossless() {
+			if let Token::EOF = tok {
+				trivia.insert(vec![], Trivia { trailing: triv.leading, ..Trivia::default() });
+			} else {
+				trivia.insert(vec![children.len()], triv);
+				children.push(self.token_node(tok, ns));
+			}
+		}
+		(Group(ns.to_int(self.name(NodeKind::Document)), children), trivia)
+	}
+}
+
+pub fn to_tree<T: Iterator<Item=char>, I: Interner>(t: Tokenizer<T>, ns: &mut I) -> Node {
+	TreeConfig::default().tree(t, ns)
+}
+
+// Like to_tree, but also returns the source span of each token's group and of
+// the atom inside it; see track::run_tracked to carry them through rewrites.
+pub fn to_tree_spanned<T: Iterator<Item=char>, I: Interner>(t: Tokenizer<T>, ns: &mut I) -> (Node, Annotations<Span>) {
+	TreeConfig::default().spanned(t, ns)
+}
+
+// Like to_tree, but keeps each token's trivia and spelling (see
+// Tokenizer::lossless) keyed by the path of its group; the document itself
+// carries whatever trails the last token. Run rules with track::run_tracked
+// and unparse the result to reproduce everything the rules didn't touch.
+pub fn to_tree_lossless<T: Iterator<Item=char>, I: Interner>(t: Tokenizer<T>, ns: &mut I) -> (Node, Annotations<Trivia>) {
+	TreeConfig::default().lossless(t, ns)
+}
+
+fn glues(a: Option<char>, b: Option<char>) -> bool {
+	let word = |c: char| c.is_alphanumeric() || c == '_';
+	a.is_some_and(word) && b.is_some_and(word)
+}
+
+// Escapes what the default lexer needs escaped inside double quotes and leaves
+// every other character as it is.
+fn push_escaped(s: &str, out: &mut String) {
+	for c in s.chars() {
+		match c {
+			'"' | '\\' => { out.push('\\'); out.push(c); },
+			'\n' => out.push_str("\\n"),
+			'\t' => out.push_str("\\t"),
+			'\r' => out.push_str("\\r"),
+			c if c.is_control() => out.push_str(&format!("\\u{{{:x}}}", c as u32)),
+			c => out.push(c),
+		}
+	}
+}
+
+struct Unparser<'a> {
+	ns: &'a Namespace,
+	string: &'a str,
+	trivia: &'a Annotations<Trivia>,
+	out: String,
+	verbatim: bool,
+}
+
+impl<'a> Unparser<'a> {
+	// Original text is copied as is; a space is only slipped in where
+	// synthesized text would otherwise run into a neighbouring word.
+	fn emit(&mut self, s: &str, verbatim: bool) {
+		if s.is_empty() {
+			return;
+		}
+		if !(verbatim && self.verbatim) && glues(self.out.chars().last(), s.chars().next()) {
+			self.out.push(' ');
+		}
+		self.out.push_str(s);
+		self.verbatim = verbatim;
+	}
+
+	fn name(&self, id: usize) -> &'a str {
+		self.ns.to_str(id).map_or("", |s| s.as_str())
+	}
+
+	fn children(&mut self, children: &[Node], path: &mut Path) {
+		for (i, child) in children.iter().enumerate() {
+			path.push(i);
+			self.node(child, path);
+			path.pop();
+		}
+	}
+
+	fn node(&mut self, node: &Node, path: &mut Path) {
+		if let Some(triv) = self.trivia.get(path) {
+			self.emit(&triv.leading, true);
+			if triv.text.is_empty() {
+				if let Group(_, ref children) = *node {
+					self.children(children, path);
+				}
+			} else {
+				self.emit(&triv.text, true);
+			}
+			self.emit(&triv.trailing, true);
+			return;
+		}
+		match *node {
+			Atom(val) => {
+				let s = self.name(val);
+				self.emit(s, false);
+			},
+			Group(val, ref children) if self.name(val) == self.string => {
+				let mut s = String::from("\"");
+				for child in children {
+					if let Atom(val) = *child {
+						push_escaped(self.name(val), &mut s);
+					}
+				}
+				s.push('"');
+				self.emit(&s, false);
 			},
-			_ => unreachable!(),
+			Group(_, ref children) => self.children(children, path),
+			_ => (),
+		}
+	}
+}
+
+// Turns a tree from to_tree_lossless back into source text. Nodes that still
+// have their trivia are reproduced exactly; anything a rule built is written
+// out from its atoms, with strings requoted.
+pub fn unparse(tree: &Node, ns: &Namespace, trivia: &Annotations<Trivia>) -> String {
+	TreeConfig::default().unparse(tree, ns, trivia)
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum BracketError {
+	Unclosed { open: char, at: Position },
+	Unopened { close: char, at: Position },
+	Mismatched { open: char, opened: Position, close: char, at: Position },
+}
+
+impl fmt::Display for BracketError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match *self {
+			BracketError::Unclosed { open, at } =>
+				write!(f, "Unclosed '{}' at line {}, column {}", open, at.line, at.column),
+			BracketError::Unopened { close, at } =>
+				write!(f, "Unexpected '{}' at line {}, column {}", close, at.line, at.column),
+			BracketError::Mismatched { open, opened, close, at } =>
+				write!(f, "'{}' at line {}, column {} closed by '{}' at line {}, column {}",
+					open, opened.line, opened.column, close, at.line, at.column),
+		}
+	}
+}
+
+const BRACKETS: [(char, char, NodeKind); 3] = [('(', ')', NodeKind::Paren), ('[', ']', NodeKind::Bracket), ('{', '}', NodeKind::Brace)];
+
+impl<'a> TreeConfig<'a> {
+	pub fn unparse(&self, tree: &Node, ns: &Namespace, trivia: &Annotations<Trivia>) -> String {
+		let mut unparser = Unparser { ns, string: self.name(NodeKind::String), trivia, out: String::new(), verbatim: true };
+		unparser.node(tree, &mut Path::new());
+		unparser.out
+	}
+
+	pub fn nested<T: Iterator<Item=char>, I: Interner>(&self, t: Tokenizer<T>, ns: &mut I) -> Result<Node, BracketError> {
+		let mut stack: Vec<(char, Position, Vec<Node>)> = Vec::new();
+		let mut children = Vec::new();
+		for (tok, span) in t.spanned() {
+			if let Token::OPER(c) = tok {
+				if let Some(&(open, _, _)) = BRACKETS.iter().find(|b| b.0 == c) {
+					stack.push((open, span.start, children));
+					children = Vec::new();
+					continue;
+				}
+				if let Some(&(_, close, _)) = BRACKETS.iter().find(|b| b.1 == c) {
+					let (open, opened, outer) = match stack.pop() {
+						Some(frame) => frame,
+						None => return Err(BracketError::Unopened { close, at: span.start }),
+					};
+					let &(_, expected, kind) = BRACKETS.iter().find(|b| b.0 == open).unwrap();
+					if close != expected {
+						return Err(BracketError::Mismatched { open, opened, close, at: span.start });
+					}
+					let group = Group(ns.to_int(self.name(kind)), children);
+					children = outer;
+					children.push(group);
+					continue;
+				}
+			}
+			children.push(self.token_node(tok, ns));
+		}
+		if let Some((open, at, _)) = stack.pop() {
+			return Err(BracketError::Unclosed { open, at });
 		}
+		Ok(Group(ns.to_int(self.name(NodeKind::Document)), children))
+	}
+}
+
+// Like to_tree, but each balanced (), [] or {} pair becomes a paren, bracket
+// or brace group holding the tokens between them; the brackets themselves
+// don't appear in the tree.
+pub fn to_nested_tree<T: Iterator<Item=char>, I: Interner>(t: Tokenizer<T>, ns: &mut I) -> Result<Node, BracketError> {
+	TreeConfig::default().nested(t, ns)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn lossless_round_trip() {
+		let src = "/* head */\nx = 'it''s' + 0x1F;\t// tail\n\n  y=\"q\\\"\" \n";
+		let mut ns = Namespace::new();
+		let (tree, trivia) = to_tree_lossless(Tokenizer::new(src.chars()), &mut ns);
+		assert_eq!(unparse(&tree, &ns, &trivia), src);
+	}
+
+	#[test]
+	fn rewrites_keep_untouched_text() {
+		let src = "f(a, d) /* d */\nx = y;\n";
+		let mut ns = Namespace::new();
+		let (tree, mut trivia) = to_tree_lossless(Tokenizer::new(src.chars()), &mut ns);
+		let (s, x, y) = (ns.to_int("s"), ns.to_int("x"), ns.to_int("y"));
+		let (oper, ident) = (ns.to_int("oper"), ns.to_int("ident"));
+		let (eq, assign) = (ns.to_int("="), ns.to_int(":="));
+		let rules = vec![
+			Rule::new(
+				Sequence(s, vec![MatchPoint(x), Group(oper, vec![Atom(eq)]), MatchPoint(y)]),
+				Sequence(s, vec![MatchPoint(y), Group(oper, vec![Atom(assign)]), MatchPoint(x)]),
+			),
+			Rule::new(
+				Sequence(s, vec![Group(ident, vec![Atom(ns.to_int("d"))])]),
+				Sequence(s, vec![Group(ns.to_int("string"), vec![Atom(ns.to_int("d\n"))])]),
+			),
+		];
+		let (result, _) = run_tracked(&tree, &rules, &mut trivia);
+		assert_eq!(unparse(&result, &ns, &trivia), "f(a, \"d\\n\") /* d */\ny:=x ;\n");
+	}
+
+	#[test]
+	fn synthesized_strings_requote() {
+		let text = "caf\u{e9}'s \"q\" \\ \n\u{1}";
+		let mut ns = Namespace::new();
+		let tree = Group(ns.to_int("document"), vec![Group(ns.to_int("string"), vec![Atom(ns.to_int(text))])]);
+		let src = unparse(&tree, &ns, &Annotations::new());
+		assert_eq!(src, "\"caf\u{e9}'s \\\"q\\\" \\\\ \\n\\u{1}\"");
+		assert_eq!(to_tree(Tokenizer::new(src.chars()), &mut ns), tree);
+	}
+
+	#[test]
+	fn nested_brackets() {
+		let mut ns = Namespace::new();
+		let tree = to_nested_tree(Tokenizer::new("f(a, [b]) {}".chars()), &mut ns).unwrap();
+		assert_eq!(sexp::write(&tree, &ns).unwrap(),
+			"(document (ident \"f\") (paren (ident \"a\") (oper \",\") (bracket (ident \"b\"))) (brace))");
+
+		let err = |src: &str| to_nested_tree(Tokenizer::new(src.chars()), &mut Namespace::new()).unwrap_err();
+		let pos = |line, column, offset| Position { offset, line, column };
+		assert_eq!(err("a\n (b]"), BracketError::Mismatched { open: '(', opened: pos(2, 2, 3), close: ']', at: pos(2, 4, 5) });
+		assert_eq!(err("{ ( ) "), BracketError::Unclosed { open: '{', at: pos(1, 1, 0) });
+		assert_eq!(err("x }"), BracketError::Unopened { close: '}', at: pos(1, 3, 2) });
+	}
+
+	#[test]
+	fn default_names() {
+		let mut ns = Namespace::new();
+		let tree = to_tree(Tokenizer::new("x 1 2.5".chars()), &mut ns);
+		assert_eq!(sexp::write(&tree, &ns).unwrap(), "(document (ident \"x\") (num \"1\") (num \"2.5\"))");
+	}
+
+	#[test]
+	fn configured_names() {
+		let config = TreeConfig::builder()
+			.name(NodeKind::Document, "program")
+			.name(NodeKind::Paren, "call")
+			.bare(NodeKind::Oper, true)
+			.bare(NodeKind::Int, true)
+			.keywords(&["if", "while"])
+			.build();
+		let mut ns = Namespace::new();
+		let src = "if (x) while y 1_0u8 'z'";
+		let lexemes = Lexemes::builder().number_suffixes(&["u8"]).build();
+		let tree = config.nested(Tokenizer::with_lexemes(src.chars(), lexemes), &mut ns).unwrap();
+		assert_eq!(sexp::write(&tree, &ns).unwrap(),
+			"(program (keyword \"if\") (call (ident \"x\")) (keyword \"while\") (ident \"y\") \"10u8\" (string \"z\"))");
+
+		let (tree, trivia) = config.lossless(Tokenizer::new("a + 'b'".chars()), &mut ns);
+		assert_eq!(sexp::write(&tree, &ns).unwrap(), "(program (ident \"a\") \"+\" (string \"b\"))");
+		assert_eq!(config.unparse(&tree, &ns, &trivia), "a + 'b'");
 	}
-	tree
 }
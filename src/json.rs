@@ -0,0 +1,599 @@
+use std::fmt;
+use std::char;
+
+use super::*;
+use ns::*;
+
+// Tree encoding:
+//   Atom         {"Atom": "text"}
+//   Group        {"Group": "name", "children": [...]}
+//   MatchPoint   {"MatchPoint": "name"}
+//   Sequence     {"Sequence": "name", "children": [...]}
+//   Conjunctor   {"Conjunctor": [...]}
+//   Disjunctor   {"Disjunctor": [...]}
+//   Negator      {"Negator": node}
+//   SplicePair   {"SplicePair": [start, len]}
+//   NoNode       null
+// A Rule is {"lhs": node, "rhs": node}, plus whichever of its metadata isn't
+// the default: "name": "text", "attributes": [["key", "value" or null], ...],
+// "enabled": false and "location": {"file": "path" or null, "line": n,
+// "column": n}. A RuleSet is an array of Rules.
+
+const MAX_DEPTH: usize = 4096;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Json {
+	Null,
+	Bool(bool),
+	Num(f64),
+	Str(String),
+	Arr(Vec<Json>),
+	Obj(Vec<(String, Json)>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum JsonError {
+	Syntax(usize, String),
+	UnknownSymbol(usize),
+	Shape(String),
+	TooDeep,
+}
+
+impl fmt::Display for JsonError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match *self {
+			JsonError::Syntax(offset, ref msg) => write!(f, "JSON syntax error at byte {}: {}", offset, msg),
+			JsonError::UnknownSymbol(id) => write!(f, "Symbol {} is not in the namespace", id),
+			JsonError::Shape(ref msg) => write!(f, "Malformed tree: {}", msg),
+			JsonError::TooDeep => write!(f, "Nesting exceeds {} levels", MAX_DEPTH),
+		}
+	}
+}
+
+impl Json {
+	pub fn parse(text: &str) -> Result<Json, JsonError> {
+		let mut parser = Parser { text, pos: 0 };
+		let value = parser.value(0)?;
+		parser.skip_ws();
+		if parser.pos != text.len() {
+			return Err(parser.error("Trailing characters after value"));
+		}
+		Ok(value)
+	}
+
+	fn get(&self, key: &str) -> Option<&Json> {
+		match *self {
+			Json::Obj(ref members) => members.iter().find(|m| m.0 == key).map(|m| &m.1),
+			_ => None,
+		}
+	}
+
+	fn write(&self, out: &mut String) {
+		match *self {
+			Json::Null => out.push_str("null"),
+			Json::Bool(b) => out.push_str(if b { "true" } else { "false" }),
+			Json::Num(n) => out.push_str(&n.to_string()),
+			Json::Str(ref s) => write_str(s, out),
+			Json::Arr(ref items) => {
+				out.push('[');
+				for (i, item) in items.iter().enumerate() {
+					if i > 0 { out.push(','); }
+					item.write(out);
+				}
+				out.push(']');
+			},
+			Json::Obj(ref members) => {
+				out.push('{');
+				for (i, (key, value)) in members.iter().enumerate() {
+					if i > 0 { out.push(','); }
+					write_str(key, out);
+					out.push(':');
+					value.write(out);
+				}
+				out.push('}');
+			},
+		}
+	}
+}
+
+impl fmt::Display for Json {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		let mut out = String::new();
+		self.write(&mut out);
+		f.write_str(&out)
+	}
+}
+
+fn write_str(s: &str, out: &mut String) {
+	out.push('"');
+	for c in s.chars() {
+		match c {
+			'"' => out.push_str("\\\""),
+			'\\' => out.push_str("\\\\"),
+			'\n' => out.push_str("\\n"),
+			'\r' => out.push_str("\\r"),
+			'\t' => out.push_str("\\t"),
+			c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+			c => out.push(c),
+		}
+	}
+	out.push('"');
+}
+
+struct Parser<'a> {
+	text: &'a str,
+	pos: usize,
+}
+
+impl<'a> Parser<'a> {
+	fn error(&self, msg: &str) -> JsonError {
+		JsonError::Syntax(self.pos, msg.to_string())
+	}
+
+	fn peek(&self) -> Option<char> {
+		self.text[self.pos..].chars().next()
+	}
+
+	fn bump(&mut self) -> Option<char> {
+		let c = self.peek();
+		if let Some(c) = c {
+			self.pos += c.len_utf8();
+		}
+		c
+	}
+
+	fn skip_ws(&mut self) {
+		while let Some(c) = self.peek() {
+			if c == ' ' || c == '\t' || c == '\r' || c == '\n' {
+				self.bump();
+			} else {
+				break;
+			}
+		}
+	}
+
+	fn expect(&mut self, c: char) -> Result<(), JsonError> {
+		self.skip_ws();
+		if self.peek() == Some(c) {
+			self.bump();
+			Ok(())
+		} else {
+			Err(self.error(&format!("Expected {:?}", c)))
+		}
+	}
+
+	fn keyword(&mut self, word: &str, value: Json) -> Result<Json, JsonError> {
+		if self.text[self.pos..].starts_with(word) {
+			self.pos += word.len();
+			Ok(value)
+		} else {
+			Err(self.error("Unknown literal"))
+		}
+	}
+
+	fn value(&mut self, depth: usize) -> Result<Json, JsonError> {
+		if depth > MAX_DEPTH {
+			return Err(JsonError::TooDeep);
+		}
+		self.skip_ws();
+		match self.peek() {
+			None => Err(self.error("Unexpected end of input")),
+			Some('n') => self.keyword("null", Json::Null),
+			Some('t') => self.keyword("true", Json::Bool(true)),
+			Some('f') => self.keyword("false", Json::Bool(false)),
+			Some('"') => self.string().map(Json::Str),
+			Some('[') => {
+				self.bump();
+				let mut items = Vec::new();
+				self.skip_ws();
+				if self.peek() == Some(']') {
+					self.bump();
+					return Ok(Json::Arr(items));
+				}
+				loop {
+					items.push(self.value(depth + 1)?);
+					self.skip_ws();
+					match self.bump() {
+						Some(',') => continue,
+						Some(']') => return Ok(Json::Arr(items)),
+						_ => return Err(self.error("Expected ',' or ']' in array")),
+					}
+				}
+			},
+			Some('{') => {
+				self.bump();
+				let mut members = Vec::new();
+				self.skip_ws();
+				if self.peek() == Some('}') {
+					self.bump();
+					return Ok(Json::Obj(members));
+				}
+				loop {
+					self.skip_ws();
+					if self.peek() != Some('"') {
+						return Err(self.error("Expected string key in object"));
+					}
+					let key = self.string()?;
+					self.expect(':')?;
+					members.push((key, self.value(depth + 1)?));
+					self.skip_ws();
+					match self.bump() {
+						Some(',') => continue,
+						Some('}') => return Ok(Json::Obj(members)),
+						_ => return Err(self.error("Expected ',' or '}' in object")),
+					}
+				}
+			},
+			Some(c) if c == '-' || c.is_ascii_digit() => {
+				let start = self.pos;
+				while let Some(c) = self.peek() {
+					if c.is_ascii_digit() || c == '-' || c == '+' || c == '.' || c == 'e' || c == 'E' {
+						self.bump();
+					} else {
+						break;
+					}
+				}
+				self.text[start..self.pos].parse::<f64>().map(Json::Num).map_err(|_| JsonError::Syntax(start, "Bad number".to_string()))
+			},
+			Some(_) => Err(self.error("Unexpected character")),
+		}
+	}
+
+	fn hex4(&mut self) -> Result<u32, JsonError> {
+		let start = self.pos;
+		for _ in 0..4 {
+			match self.bump() {
+				Some(c) if c.is_ascii_hexdigit() => (),
+				_ => return Err(self.error("Bad \\u escape")),
+			}
+		}
+		Ok(u32::from_str_radix(&self.text[start..self.pos], 16).unwrap())
+	}
+
+	fn string(&mut self) -> Result<String, JsonError> {
+		self.bump();
+		let mut value = String::new();
+		loop {
+			match self.bump() {
+				None => return Err(self.error("Unexpected end of input in string")),
+				Some('"') => return Ok(value),
+				Some('\\') => {
+					match self.bump() {
+						Some('"') => value.push('"'),
+						Some('\\') => value.push('\\'),
+						Some('/') => value.push('/'),
+						Some('b') => value.push('\u{8}'),
+						Some('f') => value.push('\u{c}'),
+						Some('n') => value.push('\n'),
+						Some('r') => value.push('\r'),
+						Some('t') => value.push('\t'),
+						Some('u') => {
+							let mut code = self.hex4()?;
+							if (0xd800..0xdc00).contains(&code) {
+								if !self.text[self.pos..].starts_with("\\u") {
+									return Err(self.error("Unpaired surrogate in \\u escape"));
+								}
+								self.pos += 2;
+								let low = self.hex4()?;
+								if !(0xdc00..0xe000).contains(&low) {
+									return Err(self.error("Unpaired surrogate in \\u escape"));
+								}
+								code = 0x10000 + ((code - 0xd800) << 10) + (low - 0xdc00);
+							}
+							match char::from_u32(code) {
+								Some(c) => value.push(c),
+								None => return Err(self.error("Bad code point in \\u escape")),
+							}
+						},
+						_ => return Err(self.error("Bad escape in string")),
+					}
+				},
+				Some(c) => value.push(c),
+			}
+		}
+	}
+}
+
+fn name_of(id: usize, ns: &Namespace) -> Result<Json, JsonError> {
+	ns.to_str(id).map(|s| Json::Str(s.clone())).ok_or(JsonError::UnknownSymbol(id))
+}
+
+fn children_to_json(children: &Vec<Node>, ns: &Namespace) -> Result<Json, JsonError> {
+	let mut items = Vec::with_capacity(children.len());
+	for child in children {
+		items.push(node_to_json(child, ns)?);
+	}
+	Ok(Json::Arr(items))
+}
+
+pub fn node_to_json(node: &Node, ns: &Namespace) -> Result<Json, JsonError> {
+	Ok(match *node {
+		Atom(val) => Json::Obj(vec![("Atom".to_string(), name_of(val, ns)?)]),
+		Group(val, ref children) => Json::Obj(vec![
+			("Group".to_string(), name_of(val, ns)?),
+			("children".to_string(), children_to_json(children, ns)?),
+		]),
+		MatchPoint(val) => Json::Obj(vec![("MatchPoint".to_string(), name_of(val, ns)?)]),
+		Sequence(val, ref children) => Json::Obj(vec![
+			("Sequence".to_string(), name_of(val, ns)?),
+			("children".to_string(), children_to_json(children, ns)?),
+		]),
+		Conjunctor(ref children) => Json::Obj(vec![("Conjunctor".to_string(), children_to_json(children, ns)?)]),
+		Disjunctor(ref children) => Json::Obj(vec![("Disjunctor".to_string(), children_to_json(children, ns)?)]),
+		Negator(ref inner) => Json::Obj(vec![("Negator".to_string(), node_to_json(inner, ns)?)]),
+		SplicePair(start, len) => Json::Obj(vec![("SplicePair".to_string(), Json::Arr(vec![Json::Num(start as f64), Json::Num(len as f64)]))]),
+		NoNode => Json::Null,
+	})
+}
+
+fn name_from(value: &Json, ns: &mut Namespace) -> Result<usize, JsonError> {
+	match *value {
+		Json::Str(ref s) => Ok(ns.to_int(s)),
+		_ => Err(JsonError::Shape(format!("Expected a symbol name, got {}", value))),
+	}
+}
+
+fn index_from(value: &Json) -> Result<usize, JsonError> {
+	match *value {
+		Json::Num(n) if n >= 0.0 && n.fract() == 0.0 => Ok(n as usize),
+		_ => Err(JsonError::Shape(format!("Expected a non-negative integer, got {}", value))),
+	}
+}
+
+fn children_from(value: Option<&Json>, ns: &mut Namespace, depth: usize) -> Result<Vec<Node>, JsonError> {
+	match value {
+		Some(Json::Arr(items)) => {
+			let mut children = Vec::with_capacity(items.len());
+			for item in items {
+				children.push(node_at(item, ns, depth + 1)?);
+			}
+			Ok(children)
+		},
+		Some(other) => Err(JsonError::Shape(format!("Expected an array of children, got {}", other))),
+		None => Err(JsonError::Shape("Missing children".to_string())),
+	}
+}
+
+pub fn node_from_json(value: &Json, ns: &mut Namespace) -> Result<Node, JsonError> {
+	node_at(value, ns, 0)
+}
+
+fn node_at(value: &Json, ns: &mut Namespace, depth: usize) -> Result<Node, JsonError> {
+	if depth > MAX_DEPTH {
+		return Err(JsonError::TooDeep);
+	}
+	let members = match *value {
+		Json::Null => return Ok(NoNode),
+		Json::Obj(ref members) => members,
+		_ => return Err(JsonError::Shape(format!("Expected a node object, got {}", value))),
+	};
+	let (kind, payload) = match members.iter().find(|m| m.0 != "children") {
+		Some(m) => (m.0.as_str(), &m.1),
+		None => return Err(JsonError::Shape(format!("Node has no kind: {}", value))),
+	};
+	let allowed = match kind {
+		"Group" | "Sequence" => 2,
+		_ => 1,
+	};
+	if members.len() != allowed {
+		return Err(JsonError::Shape(format!("Unexpected members in {} node: {}", kind, value)));
+	}
+	Ok(match kind {
+		"Atom" => Atom(name_from(payload, ns)?),
+		"Group" => Group(name_from(payload, ns)?, children_from(value.get("children"), ns, depth)?),
+		"MatchPoint" => MatchPoint(name_from(payload, ns)?),
+		"Sequence" => Sequence(name_from(payload, ns)?, children_from(value.get("children"), ns, depth)?),
+		"Conjunctor" => Conjunctor(children_from(Some(payload), ns, depth)?),
+		"Disjunctor" => Disjunctor(children_from(Some(payload), ns, depth)?),
+		"Negator" => Negator(Box::new(node_at(payload, ns, depth + 1)?)),
+		"SplicePair" => match *payload {
+			Json::Arr(ref pair) if pair.len() == 2 => SplicePair(index_from(&pair[0])?, index_from(&pair[1])?),
+			_ => return Err(JsonError::Shape(format!("Expected [start, len], got {}", payload))),
+		},
+		_ => return Err(JsonError::Shape(format!("Unknown node kind {:?}", kind))),
+	})
+}
+
+fn opt_str(value: &Option<String>) -> Json {
+	value.as_ref().map_or(Json::Null, |s| Json::Str(s.clone()))
+}
+
+fn opt_str_from(value: &Json) -> Result<Option<String>, JsonError> {
+	match *value {
+		Json::Null => Ok(None),
+		Json::Str(ref s) => Ok(Some(s.clone())),
+		_ => Err(JsonError::Shape(format!("Expected a string or null, got {}", value))),
+	}
+}
+
+pub fn rule_to_json(rule: &Rule, ns: &Namespace) -> Result<Json, JsonError> {
+	let mut members = vec![
+		("lhs".to_string(), node_to_json(&rule.lhs, ns)?),
+		("rhs".to_string(), node_to_json(&rule.rhs, ns)?),
+	];
+	let meta = &rule.meta;
+	if let Some(ref name) = meta.name {
+		members.push(("name".to_string(), Json::Str(name.clone())));
+	}
+	if !meta.attributes.is_empty() {
+		members.push(("attributes".to_string(), Json::Arr(meta.attributes.iter().map(|(k, v)| {
+			Json::Arr(vec![Json::Str(k.clone()), opt_str(v)])
+		}).collect())));
+	}
+	if !meta.enabled {
+		members.push(("enabled".to_string(), Json::Bool(false)));
+	}
+	if let Some(ref loc) = meta.location {
+		members.push(("location".to_string(), Json::Obj(vec![
+			("file".to_string(), opt_str(&loc.file)),
+			("line".to_string(), Json::Num(loc.line as f64)),
+			("column".to_string(), Json::Num(loc.column as f64)),
+		])));
+	}
+	Ok(Json::Obj(members))
+}
+
+fn meta_from_json(value: &Json) -> Result<RuleMeta, JsonError> {
+	let mut meta = RuleMeta::default();
+	if let Some(name) = value.get("name") {
+		meta.name = opt_str_from(name)?;
+	}
+	match value.get("attributes") {
+		None => (),
+		Some(Json::Arr(items)) => for item in items {
+			match *item {
+				Json::Arr(ref pair) if pair.len() == 2 => match pair[0] {
+					Json::Str(ref key) => meta.attributes.push((key.clone(), opt_str_from(&pair[1])?)),
+					_ => return Err(JsonError::Shape(format!("Expected an attribute name, got {}", pair[0]))),
+				},
+				_ => return Err(JsonError::Shape(format!("Expected [key, value], got {}", item))),
+			}
+		},
+		Some(other) => return Err(JsonError::Shape(format!("Expected an array of attributes, got {}", other))),
+	}
+	match value.get("enabled") {
+		None => (),
+		Some(&Json::Bool(enabled)) => meta.enabled = enabled,
+		Some(other) => return Err(JsonError::Shape(format!("Expected a boolean, got {}", other))),
+	}
+	if let Some(loc) = value.get("location") {
+		match (loc.get("line"), loc.get("column")) {
+			(Some(line), Some(column)) => meta.location = Some(Location {
+				file: loc.get("file").map_or(Ok(None), opt_str_from)?,
+				line: index_from(line)?,
+				column: index_from(column)?,
+			}),
+			_ => return Err(JsonError::Shape(format!("Expected a location with line and column, got {}", loc))),
+		}
+	}
+	Ok(meta)
+}
+
+pub fn rule_from_json(value: &Json, ns: &mut Namespace) -> Result<Rule, JsonError> {
+	match (value.get("lhs"), value.get("rhs")) {
+		(Some(lhs), Some(rhs)) => {
+			let mut rule = Rule::new(node_from_json(lhs, ns)?, node_from_json(rhs, ns)?);
+			rule.meta = meta_from_json(value)?;
+			Ok(rule)
+		},
+		_ => Err(JsonError::Shape(format!("Expected a rule with lhs and rhs, got {}", value))),
+	}
+}
+
+pub fn ruleset_to_json(rules: &RuleSet, ns: &Namespace) -> Result<Json, JsonError> {
+	let mut items = Vec::with_capacity(rules.len());
+	for rule in rules {
+		items.push(rule_to_json(rule, ns)?);
+	}
+	Ok(Json::Arr(items))
+}
+
+pub fn ruleset_from_json(value: &Json, ns: &mut Namespace) -> Result<RuleSet, JsonError> {
+	match *value {
+		Json::Arr(ref items) => {
+			let mut rules = RuleSet::with_capacity(items.len());
+			for item in items {
+				rules.push(rule_from_json(item, ns)?);
+			}
+			Ok(rules)
+		},
+		_ => Err(JsonError::Shape(format!("Expected an array of rules, got {}", value))),
+	}
+}
+
+pub fn encode_node(node: &Node, ns: &Namespace) -> Result<String, JsonError> {
+	node_to_json(node, ns).map(|j| j.to_string())
+}
+
+pub fn decode_node(text: &str, ns: &mut Namespace) -> Result<Node, JsonError> {
+	node_from_json(&Json::parse(text)?, ns)
+}
+
+pub fn encode_ruleset(rules: &RuleSet, ns: &Namespace) -> Result<String, JsonError> {
+	ruleset_to_json(rules, ns).map(|j| j.to_string())
+}
+
+pub fn decode_ruleset(text: &str, ns: &mut Namespace) -> Result<RuleSet, JsonError> {
+	ruleset_from_json(&Json::parse(text)?, ns)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::thread;
+	use ttr::*;
+
+	#[test]
+	fn node_round_trip() {
+		let mut ns = Namespace::new();
+		let tree = Group(ns.to_int("document"), vec![
+			Atom(ns.to_int("a \"quoted\"\n\u{1F600}")),
+			Sequence(ns.to_int("s"), vec![MatchPoint(ns.to_int("x")), Group(ns.to_int("empty"), vec![])]),
+			Conjunctor(vec![MatchPoint(ns.to_int("x")), Negator(Box::new(Atom(ns.to_int("y"))))]),
+			Disjunctor(vec![Atom(ns.to_int("y")), NoNode]),
+			SplicePair(3, 2),
+		]);
+		let text = encode_node(&tree, &ns).unwrap();
+		println!("{}", text);
+		let mut other = Namespace::new();
+		other.to_int("unrelated");
+		let decoded = decode_node(&text, &mut other).unwrap();
+		assert_eq!(encode_node(&decoded, &other).unwrap(), text);
+	}
+
+	#[test]
+	fn ruleset_round_trip() {
+		let mut ns = Namespace::new();
+		let rules = make_ttr_rules(&mut ns);
+		let text = encode_ruleset(&rules, &ns).unwrap();
+		let decoded = decode_ruleset(&text, &mut ns).unwrap();
+		assert_eq!(rules.len(), decoded.len());
+		for (a, b) in rules.iter().zip(&decoded) {
+			assert_eq!(a.lhs, b.lhs);
+			assert_eq!(a.rhs, b.rhs);
+		}
+	}
+
+	#[test]
+	fn rule_meta_round_trip() {
+		let mut ns = Namespace::new();
+		let mut rules = parse_rules("[note = \"x\", flag] tidy: 'a' -> 'b';\n'c' -> 'd';", &mut ns).unwrap();
+		rules[0].meta.location.as_mut().unwrap().file = Some("math.tt".to_string());
+		set_enabled(&mut rules, "tidy", false);
+		let text = encode_ruleset(&rules, &ns).unwrap();
+		let mut decoded = decode_ruleset(&text, &mut ns).unwrap();
+		for (a, b) in rules.iter().zip(&decoded) {
+			assert_eq!(a.meta, b.meta);
+		}
+		assert_eq!(set_enabled(&mut decoded, "tidy", true), 1);
+		// Rules written before metadata existed still decode
+		let bare = decode_ruleset("[{\"lhs\": {\"Atom\": \"a\"}, \"rhs\": null}]", &mut ns).unwrap();
+		assert_eq!(bare[0].meta, RuleMeta::default());
+		assert!(decode_ruleset("[{\"lhs\": null, \"rhs\": null, \"enabled\": 1}]", &mut ns).is_err());
+	}
+
+	#[test]
+	fn decode_errors() {
+		let mut ns = Namespace::new();
+		assert!(decode_node("{\"Atom\": 3}", &mut ns).is_err());
+		assert!(decode_node("{\"Group\": \"g\"}", &mut ns).is_err());
+		assert!(decode_node("{\"Bogus\": \"g\"}", &mut ns).is_err());
+		assert!(decode_node("[", &mut ns).is_err());
+		assert!(encode_node(&Atom(42), &ns).is_err());
+	}
+
+	#[test]
+	fn depth_limit() {
+		// Debug builds need more than the default test stack to get that deep
+		thread::Builder::new().stack_size(64 << 20).spawn(|| {
+			let mut ns = Namespace::new();
+			assert_eq!(Json::parse(&"[".repeat(100000)), Err(JsonError::TooDeep));
+			assert!(Json::parse(&format!("{}{}", "[".repeat(MAX_DEPTH), "]".repeat(MAX_DEPTH))).is_ok());
+			let mut value = Json::Null;
+			for _ in 0..MAX_DEPTH + 1 {
+				value = Json::Obj(vec![("Negator".to_string(), value)]);
+			}
+			assert_eq!(node_from_json(&value, &mut ns), Err(JsonError::TooDeep));
+		}).unwrap().join().unwrap();
+	}
+}
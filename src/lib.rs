@@ -1,13 +1,21 @@
 use std::boxed::Box;
+use std::fmt;
 use std::iter;
 use std::sync::RwLock;
 
 extern crate hamt;
 use hamt::HamtMap;
 
+extern crate unicode_xid;
+
+pub mod binfmt;
 pub mod ctok;
 pub mod ctree;
+pub mod json;
+pub mod lint;
 pub mod ns;
+pub mod sexp;
+pub mod track;
 pub mod ttr;
 
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -158,15 +166,77 @@ impl Node {
 	}
 }
 
+// Where a rule was written: the file (when it came from one) and the line
+// and column its first token starts at.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Location {
+	pub file: Option<String>,
+	pub line: usize,
+	pub column: usize,
+}
+
+impl fmt::Display for Location {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		if let Some(ref file) = self.file {
+			write!(f, "{}:", file)?;
+		}
+		write!(f, "{}:{}", self.line, self.column)
+	}
+}
+
+// Everything about a rule that doesn't affect what it matches. Disabled rules
+// stay in their RuleSet, so indices don't shift, but never fire.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RuleMeta {
+	pub name: Option<String>,
+	pub location: Option<Location>,
+	pub attributes: Vec<(String, Option<String>)>,
+	pub enabled: bool,
+}
+
+impl Default for RuleMeta {
+	fn default() -> RuleMeta {
+		RuleMeta { name: None, location: None, attributes: Vec::new(), enabled: true }
+	}
+}
+
 #[derive(Debug, Clone)]
 pub struct Rule {
 	pub lhs: Node,
 	pub rhs: Node,
+	pub meta: RuleMeta,
 }
 
 pub type RuleSet = Vec<Rule>;
 
 impl Rule {
+	pub fn new(lhs: Node, rhs: Node) -> Rule {
+		Rule { lhs, rhs, meta: RuleMeta::default() }
+	}
+
+	pub fn named(mut self, name: &str) -> Rule {
+		self.meta.name = Some(name.to_string());
+		self
+	}
+
+	// The value of an attribute; a bare attribute like [disabled] is Some(None).
+	pub fn attribute(&self, key: &str) -> Option<Option<&str>> {
+		self.meta.attributes.iter().find(|(k, _)| k == key).map(|(_, v)| v.as_deref())
+	}
+
+	// How diagnostics refer to the rule at `index` of its set, e.g.
+	// "rule `fold_add` at math.tt:12:1", falling back to "rule 3".
+	pub fn describe(&self, index: usize) -> String {
+		let mut out = match self.meta.name {
+			Some(ref name) => format!("rule `{}`", name),
+			None => format!("rule {}", index),
+		};
+		if let Some(ref loc) = self.meta.location {
+			out.push_str(&format!(" at {}", loc));
+		}
+		out
+	}
+
 	pub fn exec(&self, tree: &Node) -> (bool, Node) {
 		let mut bindings = Bindings::new();
 		let result = self.lhs.matches(tree, bindings);
@@ -176,8 +246,18 @@ impl Rule {
 	}
 }
 
+// Turns every rule called `name` on or off, returning how many there were.
+pub fn set_enabled(rules: &mut RuleSet, name: &str, enabled: bool) -> usize {
+	let mut count = 0;
+	for rule in rules.iter_mut().filter(|r| r.meta.name.as_deref() == Some(name)) {
+		rule.meta.enabled = enabled;
+		count += 1;
+	}
+	count
+}
+
 pub fn pass(tree: Node, rules: &RuleSet) -> (bool, Node) {
-	for rule in rules {
+	for rule in rules.iter().filter(|r| r.meta.enabled) {
 		let (changed, node) = rule.exec(&tree);
 		if changed {
 			return (changed, node)
@@ -208,7 +288,7 @@ mod tests {
 	#[test]
 	fn simple_seq() {
 		let mut tree = Group(1, vec![Atom(1), Atom(3), Atom(2), Atom(3), Atom(3), Atom(1)]);
-		let rules = vec![Rule { lhs: Sequence(1, vec![Atom(3)]), rhs: Sequence(1, vec![Atom(4), Atom(5)]) }];
+		let rules = vec![Rule::new(Sequence(1, vec![Atom(3)]), Sequence(1, vec![Atom(4), Atom(5)]))];
 		println!("{:?}", tree);
 		let result = super::run(&tree, &rules);
 		tree = result.0;
@@ -220,7 +300,7 @@ mod tests {
 	#[test]
 	fn simple_seq_group() {
 		let mut tree = Group(1, vec![Atom(2), Group(2, vec![Atom(1), Atom(5), Group(3, vec![])]), Atom(1), Group(2, vec![Atom(1)]), Atom(3)]);
-		let rules = vec![Rule { lhs: Sequence(1, vec![Group(2, vec![Atom(1)])]), rhs: Sequence(1, vec![Group(3, vec![Group(4, vec![Atom(1)])])]) }];
+		let rules = vec![Rule::new(Sequence(1, vec![Group(2, vec![Atom(1)])]), Sequence(1, vec![Group(3, vec![Group(4, vec![Atom(1)])])]))];
 		println!("{:?}", tree);
 		let result = super::run(&tree, &rules);
 		tree = result.0;
@@ -228,4 +308,19 @@ mod tests {
 		println!("In {} iterations", result.1);
 		assert_eq!(tree, Group(1, vec![Atom(2), Group(2, vec![Atom(1), Atom(5), Group(3, vec![])]), Atom(1), Group(3, vec![Group(4, vec![Atom(1)])]), Atom(3)]));
 	}
+
+	#[test]
+	fn disabled_rules() {
+		let tree = Group(1, vec![Atom(1), Atom(2)]);
+		let mut rules = vec![
+			Rule::new(Sequence(1, vec![Atom(1)]), Sequence(1, vec![Atom(3)])).named("one"),
+			Rule::new(Sequence(1, vec![Atom(2)]), Sequence(1, vec![Atom(4)])).named("two"),
+		];
+		assert_eq!(set_enabled(&mut rules, "one", false), 1);
+		assert_eq!(set_enabled(&mut rules, "three", false), 0);
+		assert_eq!(run(&tree, &rules).0, Group(1, vec![Atom(1), Atom(4)]));
+		assert_eq!(rules[0].describe(0), "rule `one`");
+		rules[1].meta.location = Some(Location { file: Some("math.tt".to_string()), line: 12, column: 1 });
+		assert_eq!(rules[1].describe(1), "rule `two` at math.tt:12:1");
+	}
 }
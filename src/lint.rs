@@ -0,0 +1,357 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+
+use super::*;
+use ns::*;
+
+// Static checks on a RuleSet for the mistakes that otherwise only show up as
+// panics in eval, or as rules that silently never fire.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LintKind {
+	// The rhs uses a match point the lhs doesn't (always) bind
+	Unbound,
+	// The rhs splices with a Sequence id the lhs bound to a node
+	NotASplice,
+	// The rhs uses a Sequence id as a match point
+	NotANode,
+	// An rhs Sequence is evaluated against something that's never a Group
+	NonGroupTarget,
+	// An rhs Sequence may be evaluated against something other than the group
+	// its lhs Sequence matched a window of, so its splice indices don't apply
+	ForeignTarget,
+	// The rhs relies on a binding made under a Negator, which is dropped
+	NegatedBinding,
+	// An earlier rule matches everything this one does
+	Unreachable,
+}
+
+impl LintKind {
+	// Errors make eval panic; the rest are only suspicious.
+	pub fn is_error(self) -> bool {
+		match self {
+			LintKind::Unbound | LintKind::NotASplice | LintKind::NotANode | LintKind::NegatedBinding
+				| LintKind::NonGroupTarget | LintKind::ForeignTarget => true,
+			LintKind::Unreachable => false,
+		}
+	}
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Lint {
+	pub kind: LintKind,
+	pub rule: usize,
+	// How the rule is named in messages, see Rule::describe
+	pub at: String,
+	pub msg: String,
+}
+
+impl fmt::Display for Lint {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "{}: {}", self.at, self.msg)
+	}
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Bound {
+	Node,
+	Splice,
+}
+
+// What the lhs leaves in the bindings once it has matched: `partial` holds
+// variables bound by only some alternatives of a Disjunctor, `negated` those
+// first bound under a Negator, whose bindings are thrown away.
+#[derive(Clone, Default)]
+struct Scope {
+	bound: HashMap<usize, Bound>,
+	partial: HashSet<usize>,
+	negated: HashSet<usize>,
+}
+
+impl Scope {
+	// Mirrors the order Node::matches binds in.
+	fn bind(&mut self, node: &Node, negated: bool) {
+		match *node {
+			MatchPoint(idx) => {
+				if negated {
+					if !self.bound.contains_key(&idx) { self.negated.insert(idx); }
+				} else {
+					self.bound.entry(idx).or_insert(Bound::Node);
+				}
+			},
+			Group(_, ref v) | Conjunctor(ref v) => for kid in v { self.bind(kid, negated) },
+			Sequence(idx, ref v) => {
+				for kid in v { self.bind(kid, negated) }
+				if negated {
+					if !self.bound.contains_key(&idx) { self.negated.insert(idx); }
+				} else {
+					self.bound.insert(idx, Bound::Splice);
+				}
+			},
+			Disjunctor(ref v) if !negated && !v.is_empty() => {
+				let alts: Vec<Scope> = v.iter().map(|kid| {
+					let mut alt = self.clone();
+					alt.bind(kid, false);
+					alt
+				}).collect();
+				for alt in &alts {
+					for (idx, kind) in &alt.bound {
+						if alts.iter().all(|a| a.bound.get(idx) == Some(kind)) {
+							self.bound.insert(*idx, *kind);
+						} else if !self.bound.contains_key(idx) {
+							self.partial.insert(*idx);
+						}
+					}
+					self.partial.extend(alt.partial.iter().cloned());
+					self.negated.extend(alt.negated.iter().cloned());
+				}
+			},
+			Disjunctor(ref v) => for kid in v { self.bind(kid, negated) },
+			Negator(ref inner) => self.bind(inner, true),
+			_ => (),
+		}
+	}
+}
+
+// Stands for the input past the end of a Group, which eval fills with NoNode.
+static PAST_END: Node = NoNode;
+
+// Whether what the lhs pattern matches is always a Group (Some(true)), never
+// one (Some(false)), or can't be told (None).
+fn group_target(pat: Option<&Node>) -> Option<bool> {
+	match pat {
+		Some(&Group(..)) | Some(&Sequence(..)) => Some(true),
+		Some(&Atom(_)) | Some(&NoNode) => Some(false),
+		Some(Conjunctor(v)) => {
+			let kids: Vec<Option<bool>> = v.iter().map(|p| group_target(Some(p))).collect();
+			if kids.contains(&Some(true)) {
+				Some(true)
+			} else if kids.contains(&Some(false)) {
+				Some(false)
+			} else { None }
+		},
+		Some(Disjunctor(v)) if !v.is_empty() => {
+			let first = group_target(v.first());
+			if v.iter().all(|p| group_target(Some(p)) == first) { first } else { None }
+		},
+		_ => None,
+	}
+}
+
+// Whether what the lhs pattern matches is always the group the Sequence with
+// id `idx` matched a window of.
+fn same_window(pat: Option<&Node>, idx: usize) -> bool {
+	match pat {
+		Some(&Sequence(id, _)) => id == idx,
+		Some(Conjunctor(v)) => v.iter().any(|p| same_window(Some(p), idx)),
+		Some(Disjunctor(v)) => !v.is_empty() && v.iter().all(|p| same_window(Some(p), idx)),
+		_ => false,
+	}
+}
+
+// The lhs pattern matched against the i'th child of what `pat` matched, if
+// that can be told statically. A Sequence shifts positions, so can't be.
+fn child_pattern(pat: Option<&Node>, i: usize) -> Option<&Node> {
+	match pat {
+		Some(Group(_, v)) => Some(v.get(i).unwrap_or(&PAST_END)),
+		Some(Conjunctor(v)) => v.iter().filter_map(|p| child_pattern(Some(p), i)).next(),
+		_ => None,
+	}
+}
+
+// Likewise for the i'th node of the window a Sequence with id `idx` matched.
+fn window_pattern(pat: Option<&Node>, idx: usize, i: usize) -> Option<&Node> {
+	match pat {
+		Some(&Sequence(id, ref v)) if id == idx => v.get(i),
+		Some(Conjunctor(v)) => v.iter().filter_map(|p| window_pattern(Some(p), idx, i)).next(),
+		_ => None,
+	}
+}
+
+struct Checker<'a> {
+	ns: &'a Namespace,
+	scope: Scope,
+	lints: Vec<(LintKind, String)>,
+}
+
+impl<'a> Checker<'a> {
+	fn var(&self, idx: usize) -> String {
+		format!("<{}>", self.ns.to_str(idx).map_or_else(|| idx.to_string(), |s| s.clone()))
+	}
+
+	fn lint(&mut self, kind: LintKind, msg: String) {
+		self.lints.push((kind, msg));
+	}
+
+	// Reports a variable the rhs needs as `want` but the lhs binds some other
+	// way, or not at all.
+	fn binding(&mut self, idx: usize, want: Bound, path: &[usize]) {
+		let name = self.var(idx);
+		match self.scope.bound.get(&idx).cloned() {
+			Some(ref kind) if *kind == want => (),
+			Some(Bound::Node) => self.lint(LintKind::NotASplice, format!("the rhs splices with {} at {:?}, but the lhs binds it to a node, not a Sequence", name, path)),
+			Some(Bound::Splice) => self.lint(LintKind::NotANode, format!("the rhs uses {} at {:?}, but the lhs binds it to a Sequence splice, not a node", name, path)),
+			None if self.scope.partial.contains(&idx) => self.lint(LintKind::Unbound, format!("the rhs uses {} at {:?}, but only some alternatives of a |[...] on the lhs bind it", name, path)),
+			None if self.scope.negated.contains(&idx) => self.lint(LintKind::NegatedBinding, format!("the rhs uses {} at {:?}, but the lhs only binds it under a '!', which drops the binding", name, path)),
+			None => self.lint(LintKind::Unbound, format!("the rhs uses {} at {:?}, which the lhs never binds", name, path)),
+		}
+	}
+
+	// Walks the rhs along with the lhs pattern each node will be evaluated
+	// against, where that's known.
+	fn rhs(&mut self, node: &Node, pat: Option<&Node>, path: &mut Vec<usize>) {
+		match *node {
+			MatchPoint(idx) => self.binding(idx, Bound::Node, path),
+			Group(_, ref v) => for (i, kid) in v.iter().enumerate() {
+				path.push(i);
+				self.rhs(kid, child_pattern(pat, i), path);
+				path.pop();
+			},
+			Sequence(idx, ref v) => {
+				self.binding(idx, Bound::Splice, path);
+				let name = self.var(idx);
+				// Only a splice binding has a window to check against
+				let spliced = self.scope.bound.get(&idx) == Some(&Bound::Splice);
+				if spliced && !same_window(pat, idx) {
+					if group_target(pat) == Some(false) {
+						self.lint(LintKind::NonGroupTarget, format!("the rhs splices with {} at {:?} into something the lhs guarantees isn't a group", name, path));
+					} else {
+						self.lint(LintKind::ForeignTarget, format!("the rhs splices with {} at {:?} into something other than the group the lhs matched {} in", name, path, name));
+					}
+				}
+				for (i, kid) in v.iter().enumerate() {
+					path.push(i);
+					self.rhs(kid, window_pattern(pat, idx, i), path);
+					path.pop();
+				}
+			},
+			_ => (),
+		}
+	}
+}
+
+fn count_vars(node: &Node, counts: &mut HashMap<usize, usize>) {
+	match *node {
+		MatchPoint(idx) => *counts.entry(idx).or_insert(0) += 1,
+		Sequence(idx, ref v) => {
+			*counts.entry(idx).or_insert(0) += 1;
+			for kid in v { count_vars(kid, counts) }
+		},
+		Group(_, ref v) | Conjunctor(ref v) | Disjunctor(ref v) => for kid in v { count_vars(kid, counts) },
+		Negator(ref inner) => count_vars(inner, counts),
+		_ => (),
+	}
+}
+
+// Whether `general` matches every tree `specific` does, as far as can be told
+// without knowing the trees; false whenever in doubt. Match points in
+// `general` must each be used once, so that they match anything.
+fn subsumes(general: &Node, specific: &Node) -> bool {
+	let windows = |gv: &Vec<Node>, sv: &Vec<Node>| {
+		gv.len() <= sv.len() && (0..sv.len() - gv.len() + 1).any(|i| gv.iter().zip(&sv[i..]).all(|(g, s)| subsumes(g, s)))
+	};
+	match (general, specific) {
+		(_, Disjunctor(sv)) => sv.iter().all(|s| subsumes(general, s)),
+		(MatchPoint(_), _) => true,
+		(Disjunctor(gv), _) => gv.iter().any(|g| subsumes(g, specific)),
+		(Conjunctor(gv), _) => gv.iter().all(|g| subsumes(g, specific)),
+		(_, Conjunctor(sv)) => sv.iter().any(|s| subsumes(general, s)),
+		(Atom(g), Atom(s)) => g == s,
+		(Group(gn, gv), Group(sn, sv)) => gn == sn && gv.len() == sv.len() && gv.iter().zip(sv).all(|(g, s)| subsumes(g, s)),
+		(Sequence(_, gv), Group(_, sv)) | (Sequence(_, gv), Sequence(_, sv)) => windows(gv, sv),
+		_ => false,
+	}
+}
+
+fn shadows(general: &Node, specific: &Node) -> bool {
+	let mut counts = HashMap::new();
+	count_vars(general, &mut counts);
+	if counts.values().all(|&n| n == 1) {
+		subsumes(general, specific)
+	} else {
+		general == specific
+	}
+}
+
+// Checks every rule in the set, in order. Disabled rules are neither checked
+// nor count as shadowing later ones.
+pub fn lint(rules: &RuleSet, ns: &Namespace) -> Vec<Lint> {
+	let mut out = Vec::new();
+	for (i, rule) in rules.iter().enumerate() {
+		if !rule.meta.enabled { continue; }
+		let mut checker = Checker { ns, scope: Scope::default(), lints: Vec::new() };
+		checker.scope.bind(&rule.lhs, false);
+		checker.rhs(&rule.rhs, Some(&rule.lhs), &mut Vec::new());
+		if let Some(j) = rules[..i].iter().position(|r| r.meta.enabled && shadows(&r.lhs, &rule.lhs)) {
+			checker.lint(LintKind::Unreachable, format!("never fires, because {} matches everything it does", rules[j].describe(j)));
+		}
+		out.extend(checker.lints.into_iter().map(|(kind, msg)| Lint { kind, rule: i, at: rule.describe(i), msg }));
+	}
+	out
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use ttr::*;
+
+	fn kinds(src: &str) -> Vec<(usize, LintKind)> {
+		let mut ns = Namespace::new();
+		let rules = parse_rules(src, &mut ns).unwrap();
+		lint(&rules, &ns).into_iter().map(|l| (l.rule, l.kind)).collect()
+	}
+
+	#[test]
+	fn ttr_grammar_is_clean() {
+		let mut ns = Namespace::new();
+		let rules = make_ttr_rules(&mut ns);
+		assert_eq!(lint(&rules, &ns), Vec::new());
+	}
+
+	#[test]
+	fn bindings() {
+		use self::LintKind::*;
+		assert_eq!(kinds("f[<x>] -> g[<x>, <y>];"), vec![(0, Unbound)]);
+		assert_eq!(kinds("f[<x>] -> <x>[];"), vec![(0, NotASplice)]);
+		assert_eq!(kinds("<s>['a'] -> g[<s>];"), vec![(0, NotANode)]);
+		assert_eq!(kinds("f[!g[<x>]] -> <x>;"), vec![(0, NegatedBinding)]);
+		assert_eq!(kinds("f[<x>, !<x>] -> <x>;"), vec![]);
+		assert_eq!(kinds("|[f[<x>], g[<y>]] -> <x>;"), vec![(0, Unbound)]);
+		assert_eq!(kinds("|[f[<x>], g[<x>]] -> <x>;"), vec![]);
+		assert_eq!(kinds("&[<n>, f[<x>]] -> g[<n>, <x>];"), vec![]);
+	}
+
+	#[test]
+	fn splice_targets() {
+		use self::LintKind::*;
+		assert_eq!(kinds("<s>['a'] -> <s>['b'];"), vec![]);
+		assert_eq!(kinds("f[<s>['a']] -> f[<s>['b']];"), vec![]);
+		assert_eq!(kinds("&[<x>, <s>['a']] -> <s>['b'];"), vec![]);
+		assert_eq!(kinds("f[<x>] -> f[<s>['b']];"), vec![(0, Unbound)]);
+		assert_eq!(kinds("f[<s>['a'], <y>] -> f[<y>, <s>['b']];"), vec![(0, ForeignTarget)]);
+		// Another group's children don't line up with the window <s> matched
+		assert_eq!(kinds("f[<s>['a'], g[<x>]] -> f[g[<x>], <s>['b']];"), vec![(0, ForeignTarget)]);
+		assert_eq!(kinds("<s>[<t>['a']] -> <t>['b'];"), vec![(0, ForeignTarget)]);
+		assert_eq!(kinds("|[f[<s>['a']], g[<s>['b']]] -> <s>['c'];"), vec![(0, ForeignTarget)]);
+		assert_eq!(kinds("|[<s>['a'], <s>['b']] -> <s>['c'];"), vec![]);
+		// An atom, or past the end of the lhs group where eval feeds in NoNode
+		assert_eq!(kinds("f[<s>['a'], 'b'] -> f['b', <s>['c']];"), vec![(0, NonGroupTarget)]);
+		assert_eq!(kinds("f[<s>['a']] -> f[<s>['b'], <s>['c']];"), vec![(0, NonGroupTarget)]);
+		assert_eq!(kinds("f[<s>['a'], &[<x>, 'b']] -> f[<x>, <s>['c']];"), vec![(0, NonGroupTarget)]);
+		assert_eq!(kinds("<s>[f[<t>['a']]] -> <s>[f[<t>['b']]];"), vec![]);
+	}
+
+	#[test]
+	fn unreachable_rules() {
+		use self::LintKind::*;
+		let mut ns = Namespace::new();
+		let rules = parse_rules("general: f[<x>, <y>] -> 'a';\nf['b', g[]] -> 'c';\nf[<x>, <x>] -> 'd';\nf['b'] -> 'e';", &mut ns).unwrap();
+		let lints = lint(&rules, &ns);
+		assert_eq!(lints.iter().map(|l| (l.rule, l.kind)).collect::<Vec<_>>(), vec![(1, Unreachable), (2, Unreachable)]);
+		assert_eq!(lints[0].to_string(), "rule 1 at 2:1: never fires, because rule `general` at 1:1 matches everything it does");
+
+		assert_eq!(kinds("f[<x>, <x>] -> 'a'; f['b', 'b'] -> 'c'; f[<x>, <x>] -> 'd';"), vec![(2, Unreachable)]);
+		assert_eq!(kinds("<s>['a'] -> 'x'; <t>['b', 'a'] -> 'y'; f['a', 'b'] -> 'z'; f[!'a'] -> 'w';"), vec![(1, Unreachable), (2, Unreachable)]);
+		assert_eq!(kinds("|['a', 'b'] -> 'x'; 'b' -> 'y'; [disabled] 'c' -> 'z'; 'c' -> 'w';"), vec![(1, Unreachable)]);
+	}
+}
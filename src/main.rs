@@ -1,7 +1,6 @@
-#![feature(io)]
-
+use std::env;
 use std::io;
-use std::io::Read;
+use std::process;
 
 extern crate rtt;
 
@@ -11,10 +10,39 @@ use rtt::ns::*;
 use rtt::ttr::*;
 use rtt::*;
 
+// With no arguments stdin is parsed as TT and the resulting tree printed;
+// given a .tt file, its rules are run over stdin instead. Any number of
+// "--disable <name>" options after the file switch named rules off.
 fn main() {
 	let mut ns = Namespace::new();
-	let rules = make_ttr_rules(&mut ns);
-	let mut tree = to_tree(Tokenizer::new(io::stdin().chars().map(|r| r.unwrap())), &mut ns);
+	let mut args = env::args().skip(1);
+	let mut rules = match args.next() {
+		None => make_ttr_rules(&mut ns),
+		Some(path) => {
+			let (rules, warnings) = load_rules(&path, &mut ns).unwrap_or_else(|e| {
+				eprintln!("{}", e);
+				process::exit(1);
+			});
+			for warning in warnings {
+				eprintln!("warning: {}", warning);
+			}
+			rules
+		},
+	};
+	while let Some(arg) = args.next() {
+		match (arg.as_str(), args.next()) {
+			("--disable", Some(name)) => if set_enabled(&mut rules, &name, false) == 0 {
+				eprintln!("No rule named {}", name);
+				process::exit(1);
+			},
+			_ => {
+				eprintln!("Usage: rtt [rules.tt [--disable <name>]...]");
+				process::exit(1);
+			},
+		}
+	}
+	let stdin = io::stdin();
+	let mut tree = to_tree(Tokenizer::from_reader(stdin.lock()), &mut ns);
 	let result = run(&tree, &rules);
 	tree = result.0;
 	println!("{} iters:", result.1);
@@ -1,5 +1,6 @@
 use std::collections::HashMap;
 use std::collections::hash_map::Entry;
+use std::sync::{Arc, RwLock};
 
 use super::*;
 
@@ -37,6 +38,14 @@ impl Namespace {
 		self.int_str.get(&key)
 	}
 
+	pub fn len(&self) -> usize {
+		self.next_int
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.next_int == 0
+	}
+
 	pub fn print(&self, node: &Node) {
 		match *node {
 			Atom(val) => print!("{:?}", self.to_str(val).unwrap_or(&self.bad_value)),
@@ -122,3 +131,146 @@ impl Namespace {
 		}
 	}
 }
+
+/// Anything that can intern strings to ids; lets tree builders work with both
+/// a private `Namespace` and a `SharedNamespace` shared between threads.
+pub trait Interner {
+	fn to_int(&mut self, key: &str) -> usize;
+}
+
+impl Interner for Namespace {
+	fn to_int(&mut self, key: &str) -> usize {
+		Namespace::to_int(self, key)
+	}
+}
+
+/// A `Namespace` that can be shared between threads. Lookups of already
+/// interned strings only take read locks; the write lock is taken only when a
+/// new string is added, so workers agree on every id.
+pub struct SharedNamespace {
+	str_int: RwLock<HashMap<String, usize>>,
+	int_str: RwLock<Vec<Arc<str>>>,
+}
+
+impl SharedNamespace {
+	pub fn new() -> SharedNamespace {
+		SharedNamespace {
+			str_int: RwLock::new(HashMap::new()),
+			int_str: RwLock::new(Vec::new()),
+		}
+	}
+
+	pub fn to_int(&self, key: &str) -> usize {
+		if let Some(&id) = self.str_int.read().unwrap().get(key) {
+			return id;
+		}
+		let mut str_int = self.str_int.write().unwrap();
+		match str_int.entry(key.to_string()) {
+			Entry::Occupied(oe) => *oe.get(),
+			Entry::Vacant(ve) => {
+				let mut int_str = self.int_str.write().unwrap();
+				let result = int_str.len();
+				int_str.push(Arc::from(key));
+				ve.insert(result);
+				result
+			}
+		}
+	}
+
+	pub fn to_str(&self, key: usize) -> Option<Arc<str>> {
+		self.int_str.read().unwrap().get(key).cloned()
+	}
+
+	pub fn len(&self) -> usize {
+		self.int_str.read().unwrap().len()
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.len() == 0
+	}
+
+	/// Copies the current contents into a `Namespace` with identical ids.
+	pub fn snapshot(&self) -> Namespace {
+		let mut ns = Namespace::new();
+		for s in self.int_str.read().unwrap().iter() {
+			ns.to_int(s);
+		}
+		ns
+	}
+
+	pub fn print(&self, node: &Node) {
+		self.snapshot().print(node);
+	}
+
+	pub fn debug_print(&self, node: &Node) {
+		self.snapshot().debug_print(node);
+	}
+}
+
+impl Default for SharedNamespace {
+	fn default() -> SharedNamespace {
+		SharedNamespace::new()
+	}
+}
+
+impl From<Namespace> for SharedNamespace {
+	fn from(ns: Namespace) -> SharedNamespace {
+		let shared = SharedNamespace::new();
+		for id in 0..ns.len() {
+			shared.to_int(ns.to_str(id).unwrap());
+		}
+		shared
+	}
+}
+
+impl Interner for SharedNamespace {
+	fn to_int(&mut self, key: &str) -> usize {
+		SharedNamespace::to_int(self, key)
+	}
+}
+
+impl Interner for &SharedNamespace {
+	fn to_int(&mut self, key: &str) -> usize {
+		SharedNamespace::to_int(self, key)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::sync::Arc;
+	use std::thread;
+
+	use super::*;
+	use ctok::*;
+	use ctree::*;
+
+	#[test]
+	fn shared_ids_agree() {
+		let mut seed = Namespace::new();
+		let (ident, x) = (seed.to_int("ident"), seed.to_int("x"));
+		let shared = Arc::new(SharedNamespace::from(seed));
+		let workers: Vec<_> = (0..4).map(|i| {
+			let shared = shared.clone();
+			thread::spawn(move || {
+				let source = format!("x y{} x z", i);
+				let mut ns = &*shared;
+				to_tree(Tokenizer::new(source.chars()), &mut ns)
+			})
+		}).collect();
+		let trees: Vec<Node> = workers.into_iter().map(|w| w.join().unwrap()).collect();
+
+		let replaced = shared.to_int("replaced");
+		let rules = vec![Rule::new(
+			Sequence(0, vec![Group(ident, vec![Atom(x)])]),
+			Sequence(0, vec![Atom(replaced)]),
+		)];
+		for (i, tree) in trees.iter().enumerate() {
+			let (result, iters) = run(tree, &rules);
+			assert_eq!(iters, 2);
+			if let Group(_, ref children) = result {
+				assert_eq!(children[0], Atom(replaced));
+				assert_eq!(children[1], Group(ident, vec![Atom(shared.to_int(&format!("y{}", i)))]));
+			} else { panic!("Not a group: {:?}", result) }
+		}
+	}
+}
@@ -0,0 +1,397 @@
+use std::fmt;
+
+use super::*;
+use ns::*;
+
+// Notation:
+//   "text"            Atom (a bare symbol in child position is also an Atom)
+//   (name child...)   Group; the name may be a bare symbol or a quoted string
+//   <name>            MatchPoint
+//   (<name> child...) Sequence
+//   (& child...)      Conjunctor
+//   (| child...)      Disjunctor
+//   (! child)         Negator
+//   (#splice s l)     SplicePair
+//   #none             NoNode
+// A ';' starts a comment that runs to the end of the line.
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SexpError {
+	pub pos: usize,
+	pub msg: String,
+}
+
+impl fmt::Display for SexpError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "S-expression error at byte {}: {}", self.pos, self.msg)
+	}
+}
+
+fn is_symbol_char(c: char) -> bool {
+	!c.is_whitespace() && !"()<>\";".contains(c)
+}
+
+fn is_reserved(s: &str) -> bool {
+	s == "&" || s == "|" || s == "!" || s.starts_with('#')
+}
+
+enum Head {
+	Name(String),
+	Seq(String),
+	Conj,
+	Disj,
+	Neg,
+	Splice,
+}
+
+struct Parser<'a, 'n> {
+	text: &'a str,
+	pos: usize,
+	ns: &'n mut Namespace,
+}
+
+impl<'a, 'n> Parser<'a, 'n> {
+	fn error<T>(&self, msg: &str) -> Result<T, SexpError> {
+		Err(SexpError { pos: self.pos, msg: msg.to_string() })
+	}
+
+	fn peek(&self) -> Option<char> {
+		self.text[self.pos..].chars().next()
+	}
+
+	fn bump(&mut self) -> Option<char> {
+		let c = self.peek();
+		if let Some(c) = c {
+			self.pos += c.len_utf8();
+		}
+		c
+	}
+
+	fn skip_ws(&mut self) {
+		while let Some(c) = self.peek() {
+			if c == ';' {
+				while let Some(c) = self.bump() {
+					if c == '\n' { break; }
+				}
+			} else if c.is_whitespace() {
+				self.bump();
+			} else {
+				break;
+			}
+		}
+	}
+
+	fn symbol(&mut self) -> Result<String, SexpError> {
+		let start = self.pos;
+		while let Some(c) = self.peek() {
+			if !is_symbol_char(c) { break; }
+			self.bump();
+		}
+		if start == self.pos {
+			return self.error("Expected a symbol");
+		}
+		Ok(self.text[start..self.pos].to_string())
+	}
+
+	fn string(&mut self) -> Result<String, SexpError> {
+		self.bump();
+		let mut value = String::new();
+		loop {
+			match self.bump() {
+				None => return self.error("Unexpected end of input in string"),
+				Some('"') => return Ok(value),
+				Some('\\') => match self.bump() {
+					Some('n') => value.push('\n'),
+					Some('t') => value.push('\t'),
+					Some('r') => value.push('\r'),
+					Some(c @ '"') | Some(c @ '\\') => value.push(c),
+					_ => return self.error("Bad escape in string"),
+				},
+				Some(c) => value.push(c),
+			}
+		}
+	}
+
+	fn name(&mut self) -> Result<String, SexpError> {
+		if self.peek() == Some('"') {
+			self.string()
+		} else {
+			self.symbol()
+		}
+	}
+
+	fn matchpoint_name(&mut self) -> Result<String, SexpError> {
+		self.bump();
+		let name = if self.peek() == Some('>') { String::new() } else { self.name()? };
+		if self.bump() != Some('>') {
+			return self.error("Expected '>' after match point name");
+		}
+		Ok(name)
+	}
+
+	fn index(&mut self) -> Result<usize, SexpError> {
+		self.skip_ws();
+		let sym = self.symbol()?;
+		match sym.parse() {
+			Ok(n) => Ok(n),
+			Err(_) => self.error("Expected a non-negative integer"),
+		}
+	}
+
+	fn node(&mut self) -> Result<Node, SexpError> {
+		self.skip_ws();
+		match self.peek() {
+			None => self.error("Unexpected end of input"),
+			Some('"') => {
+				let s = self.string()?;
+				Ok(Atom(self.ns.to_int(&s)))
+			},
+			Some('<') => {
+				let s = self.matchpoint_name()?;
+				Ok(MatchPoint(self.ns.to_int(&s)))
+			},
+			Some('(') => {
+				self.bump();
+				self.skip_ws();
+				let head = match self.peek() {
+					Some('<') => Head::Seq(self.matchpoint_name()?),
+					Some('"') => Head::Name(self.string()?),
+					_ => {
+						let sym = self.symbol()?;
+						match sym.as_str() {
+							"&" => Head::Conj,
+							"|" => Head::Disj,
+							"!" => Head::Neg,
+							"#splice" => Head::Splice,
+							s if is_reserved(s) => return self.error("Unknown reserved head symbol"),
+							_ => Head::Name(sym),
+						}
+					},
+				};
+				if let Head::Splice = head {
+					let (start, len) = (self.index()?, self.index()?);
+					self.skip_ws();
+					if self.bump() != Some(')') {
+						return self.error("Expected ')' after splice pair");
+					}
+					return Ok(SplicePair(start, len));
+				}
+				let mut children = Vec::new();
+				loop {
+					self.skip_ws();
+					if self.peek() == Some(')') {
+						self.bump();
+						break;
+					}
+					children.push(self.node()?);
+				}
+				Ok(match head {
+					Head::Name(s) => Group(self.ns.to_int(&s), children),
+					Head::Seq(s) => Sequence(self.ns.to_int(&s), children),
+					Head::Conj => Conjunctor(children),
+					Head::Disj => Disjunctor(children),
+					Head::Neg => {
+						if children.len() != 1 {
+							return self.error("A negator takes exactly one child");
+						}
+						Negator(Box::new(children.pop().unwrap()))
+					},
+					Head::Splice => unreachable!(),
+				})
+			},
+			Some(')') | Some('>') => self.error("Unexpected closing delimiter"),
+			Some(_) => {
+				let sym = self.symbol()?;
+				if sym == "#none" {
+					return Ok(NoNode);
+				}
+				if is_reserved(&sym) {
+					return self.error("Reserved symbol in child position");
+				}
+				Ok(Atom(self.ns.to_int(&sym)))
+			},
+		}
+	}
+}
+
+pub fn parse(text: &str, ns: &mut Namespace) -> Result<Node, SexpError> {
+	let mut parser = Parser { text, pos: 0, ns };
+	let node = parser.node()?;
+	parser.skip_ws();
+	if parser.pos != text.len() {
+		return parser.error("Trailing input after expression");
+	}
+	Ok(node)
+}
+
+pub fn parse_all(text: &str, ns: &mut Namespace) -> Result<Vec<Node>, SexpError> {
+	let mut parser = Parser { text, pos: 0, ns };
+	let mut nodes = Vec::new();
+	loop {
+		parser.skip_ws();
+		if parser.pos == text.len() {
+			return Ok(nodes);
+		}
+		nodes.push(parser.node()?);
+	}
+}
+
+fn write_string(s: &str, out: &mut String) {
+	out.push('"');
+	for c in s.chars() {
+		match c {
+			'"' => out.push_str("\\\""),
+			'\\' => out.push_str("\\\\"),
+			'\n' => out.push_str("\\n"),
+			'\t' => out.push_str("\\t"),
+			'\r' => out.push_str("\\r"),
+			c => out.push(c),
+		}
+	}
+	out.push('"');
+}
+
+// Ids that aren't in the namespace have no notation that parse would read
+// back, so they can't be written.
+#[derive(Debug, Clone, PartialEq)]
+pub struct WriteError {
+	pub id: usize,
+}
+
+impl fmt::Display for WriteError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "Symbol {} is not in the namespace", self.id)
+	}
+}
+
+fn lookup(id: usize, ns: &Namespace) -> Result<&String, WriteError> {
+	ns.to_str(id).ok_or(WriteError { id })
+}
+
+fn write_name(id: usize, ns: &Namespace, out: &mut String) -> Result<(), WriteError> {
+	match lookup(id, ns)? {
+		s if !s.is_empty() && s.chars().all(is_symbol_char) && !is_reserved(s) => out.push_str(s),
+		s => write_string(s, out),
+	}
+	Ok(())
+}
+
+fn write_matchpoint(id: usize, ns: &Namespace, out: &mut String) -> Result<(), WriteError> {
+	out.push('<');
+	if !lookup(id, ns)?.is_empty() {
+		write_name(id, ns, out)?;
+	}
+	out.push('>');
+	Ok(())
+}
+
+fn write_children(children: &[Node], ns: &Namespace, out: &mut String) -> Result<(), WriteError> {
+	for child in children {
+		out.push(' ');
+		write_node(child, ns, out)?;
+	}
+	out.push(')');
+	Ok(())
+}
+
+fn write_node(node: &Node, ns: &Namespace, out: &mut String) -> Result<(), WriteError> {
+	match *node {
+		Atom(val) => write_string(lookup(val, ns)?, out),
+		Group(val, ref children) => {
+			out.push('(');
+			write_name(val, ns, out)?;
+			write_children(children, ns, out)?;
+		},
+		MatchPoint(val) => write_matchpoint(val, ns, out)?,
+		Sequence(val, ref children) => {
+			out.push('(');
+			write_matchpoint(val, ns, out)?;
+			write_children(children, ns, out)?;
+		},
+		Conjunctor(ref children) => {
+			out.push_str("(&");
+			write_children(children, ns, out)?;
+		},
+		Disjunctor(ref children) => {
+			out.push_str("(|");
+			write_children(children, ns, out)?;
+		},
+		Negator(ref inner) => {
+			out.push_str("(! ");
+			write_node(inner, ns, out)?;
+			out.push(')');
+		},
+		SplicePair(start, len) => out.push_str(&format!("(#splice {} {})", start, len)),
+		NoNode => out.push_str("#none"),
+	}
+	Ok(())
+}
+
+pub fn write(node: &Node, ns: &Namespace) -> Result<String, WriteError> {
+	let mut out = String::new();
+	write_node(node, ns, &mut out)?;
+	Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn parse_document() {
+		let mut ns = Namespace::new();
+		let tree = parse("(document (ident \"foo\") (oper \"+\"))", &mut ns).unwrap();
+		assert_eq!(tree, Group(ns.to_int("document"), vec![
+			Group(ns.to_int("ident"), vec![Atom(ns.to_int("foo"))]),
+			Group(ns.to_int("oper"), vec![Atom(ns.to_int("+"))]),
+		]));
+	}
+
+	#[test]
+	fn pattern_round_trip() {
+		let mut ns = Namespace::new();
+		let text = "(<s> (\"odd name\" <x> \"atom\") (& <x> (! \"y\")) (| <> #none) (#splice 2 1) (empty))";
+		let tree = parse(text, &mut ns).unwrap();
+		assert_eq!(tree, Sequence(ns.to_int("s"), vec![
+			Group(ns.to_int("odd name"), vec![MatchPoint(ns.to_int("x")), Atom(ns.to_int("atom"))]),
+			Conjunctor(vec![MatchPoint(ns.to_int("x")), Negator(Box::new(Atom(ns.to_int("y"))))]),
+			Disjunctor(vec![MatchPoint(ns.to_int("")), NoNode]),
+			SplicePair(2, 1),
+			Group(ns.to_int("empty"), vec![]),
+		]));
+		assert_eq!(write(&tree, &ns).unwrap(), text);
+		assert_eq!(parse(&write(&tree, &ns).unwrap(), &mut ns).unwrap(), tree);
+	}
+
+	#[test]
+	fn seq_rule_from_sexp() {
+		let mut ns = Namespace::new();
+		let nodes = parse_all("
+			; replace every 3 with 4 5
+			(<s> \"3\")
+			(<s> \"4\" \"5\")
+			(g 1 3 2)
+		", &mut ns).unwrap();
+		let rules = vec![Rule::new(nodes[0].clone(), nodes[1].clone())];
+		let (tree, _) = run(&nodes[2], &rules);
+		assert_eq!(write(&tree, &ns).unwrap(), "(g \"1\" \"4\" \"5\" \"2\")");
+	}
+
+	#[test]
+	fn parse_errors() {
+		let mut ns = Namespace::new();
+		assert!(parse("(a", &mut ns).is_err());
+		assert!(parse("(! a b)", &mut ns).is_err());
+		assert!(parse("a b", &mut ns).is_err());
+		assert!(parse("(#bogus)", &mut ns).is_err());
+	}
+
+	#[test]
+	fn unknown_ids() {
+		let mut ns = Namespace::new();
+		let g = ns.to_int("g");
+		assert_eq!(write(&Group(g, vec![Atom(1000)]), &ns), Err(WriteError { id: 1000 }));
+		assert_eq!(write(&Group(1001, vec![]), &ns), Err(WriteError { id: 1001 }));
+		assert_eq!(write(&MatchPoint(1002), &ns).unwrap_err().to_string(), "Symbol 1002 is not in the namespace");
+	}
+}
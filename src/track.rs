@@ -0,0 +1,187 @@
+use std::collections::{BTreeMap, HashMap};
+
+use super::*;
+
+// Side tables of per-node data (source spans, trivia, ...) keyed by the path
+// of child indices from the root, kept up to date across rewrites. A node
+// keeps its annotations when the rhs reproduces it through a MatchPoint, or
+// when it's a sibling left in place by a Sequence splice; nodes built by the
+// rhs itself start out unannotated. Paths are kept sorted so that a subtree's
+// annotations sit together, starting at its own path.
+
+pub type Path = Vec<usize>;
+pub type Annotations<V> = BTreeMap<Path, V>;
+
+fn child_path(path: &Path, idx: usize) -> Path {
+	let mut p = path.clone();
+	p.push(idx);
+	p
+}
+
+fn bind_paths(pat: &Node, node: &Node, path: &Path, bindings: &Bindings, out: &mut HashMap<usize, Path>) {
+	match *pat {
+		MatchPoint(idx) => {
+			out.entry(idx).or_insert_with(|| path.clone());
+		},
+		Group(_, ref lvec) => {
+			if let Group(_, ref rvec) = *node {
+				for (i, (lref, rref)) in lvec.iter().zip(rvec).enumerate() {
+					bind_paths(lref, rref, &child_path(path, i), bindings, out);
+				}
+			}
+		},
+		Sequence(idx, ref lvec) => {
+			if let (Group(_, ref rvec), Some(rwsref)) = (node, bindings.find(&idx)) {
+				if let SplicePair(sidx, _) = *rwsref.read().unwrap() {
+					for (i, (lref, rref)) in lvec.iter().zip(rvec.iter().skip(sidx)).enumerate() {
+						bind_paths(lref, rref, &child_path(path, sidx + i), bindings, out);
+					}
+				}
+			}
+		},
+		Conjunctor(ref lvec) => {
+			for lref in lvec {
+				bind_paths(lref, node, path, bindings, out);
+			}
+		},
+		Disjunctor(ref lvec) => {
+			if let Some(lref) = lvec.iter().find(|l| l.matches(node, bindings.clone()).0) {
+				bind_paths(lref, node, path, bindings, out);
+			}
+		},
+		_ => (),
+	}
+}
+
+fn copy_subtree<V: Clone>(from: &Path, to: &Path, old: &Annotations<V>, new: &mut Annotations<V>) {
+	for (key, value) in old.range(from.clone()..).take_while(|&(key, _)| key.starts_with(from)) {
+		let mut dest = to.clone();
+		dest.extend_from_slice(&key[from.len()..]);
+		new.insert(dest, value.clone());
+	}
+}
+
+struct Tracer<'a, V: 'a> {
+	bindings: &'a Bindings,
+	bound: HashMap<usize, Path>,
+	old: &'a Annotations<V>,
+	new: Annotations<V>,
+}
+
+impl<'a, V: Clone> Tracer<'a, V> {
+	// Mirrors Node::eval: `other` is the input node evaluated against, found
+	// at `in_path` (if it exists in the input at all).
+	fn trace(&mut self, rhs: &Node, other: &Node, in_path: Option<&Path>, out_path: &Path) {
+		match *rhs {
+			MatchPoint(idx) => {
+				if let Some(from) = self.bound.get(&idx).cloned() {
+					copy_subtree(&from, out_path, self.old, &mut self.new);
+				}
+			},
+			Group(_, ref lvec) => {
+				let empty = Vec::new();
+				let rvec = if let Group(_, ref rvec) = *other { rvec } else { &empty };
+				for (i, lref) in lvec.iter().enumerate() {
+					let child_in = in_path.filter(|_| i < rvec.len()).map(|p| child_path(p, i));
+					let rref = rvec.get(i).unwrap_or(&NoNode);
+					self.trace(lref, rref, child_in.as_ref(), &child_path(out_path, i));
+				}
+			},
+			Sequence(idx, ref lvec) => {
+				let (rvec, in_path) = match (other, in_path) {
+					(Group(_, rvec), Some(in_path)) => (rvec, in_path),
+					_ => return,
+				};
+				let (sidx, slen) = match self.bindings.find(&idx).map(|r| r.read().unwrap().clone()) {
+					Some(SplicePair(sidx, slen)) => (sidx, slen),
+					_ => return,
+				};
+				if let Some(value) = self.old.get(in_path) {
+					self.new.insert(out_path.clone(), value.clone());
+				}
+				for i in 0..sidx {
+					copy_subtree(&child_path(in_path, i), &child_path(out_path, i), self.old, &mut self.new);
+				}
+				for (i, lref) in lvec.iter().enumerate() {
+					let child_in = if sidx + i < rvec.len() { Some(child_path(in_path, sidx + i)) } else { None };
+					let rref = rvec.get(sidx + i).unwrap_or(&NoNode);
+					self.trace(lref, rref, child_in.as_ref(), &child_path(out_path, sidx + i));
+				}
+				for i in (sidx + slen)..rvec.len() {
+					copy_subtree(&child_path(in_path, i), &child_path(out_path, i + lvec.len() - slen), self.old, &mut self.new);
+				}
+			},
+			_ => (),
+		}
+	}
+}
+
+pub fn exec_tracked<V: Clone>(rule: &Rule, tree: &Node, ann: &Annotations<V>) -> Option<(Node, Annotations<V>)> {
+	let (matched, bindings) = rule.lhs.matches(tree, Bindings::new());
+	if !matched { return None; }
+	let result = rule.rhs.eval(tree, &bindings);
+	let root = Path::new();
+	let mut bound = HashMap::new();
+	bind_paths(&rule.lhs, tree, &root, &bindings, &mut bound);
+	let mut tracer = Tracer { bindings: &bindings, bound, old: ann, new: Annotations::new() };
+	tracer.trace(&rule.rhs, tree, Some(&root), &root);
+	Some((result, tracer.new))
+}
+
+pub fn pass_tracked<V: Clone>(tree: &Node, rules: &RuleSet, ann: &Annotations<V>) -> Option<(Node, Annotations<V>)> {
+	rules.iter().filter(|r| r.meta.enabled).filter_map(|rule| exec_tracked(rule, tree, ann)).next()
+}
+
+pub fn run_tracked<V: Clone>(tree: &Node, rules: &RuleSet, ann: &mut Annotations<V>) -> (Node, u32) {
+	let mut iters = 0u32;
+	let mut mtree = tree.clone();
+	while let Some((node, new_ann)) = pass_tracked(&mtree, rules, ann) {
+		mtree = node;
+		*ann = new_ann;
+		iters += 1;
+	}
+	(mtree, iters)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use ctok::*;
+	use ctree::*;
+	use ns::*;
+
+	#[test]
+	fn copies_only_the_subtree() {
+		let old: Annotations<u32> = vec![
+			(vec![0], 1), (vec![0, 1], 2), (vec![0, 1, 0], 3), (vec![0, 1, 0, 5], 4), (vec![0, 2], 5), (vec![1, 1], 6),
+		].into_iter().collect();
+		let mut new = Annotations::new();
+		copy_subtree(&vec![0, 1], &vec![3], &old, &mut new);
+		let expect: Annotations<u32> = vec![(vec![3], 2), (vec![3, 0], 3), (vec![3, 0, 5], 4)].into_iter().collect();
+		assert_eq!(new, expect);
+	}
+
+	#[test]
+	fn spans_follow_matchpoints() {
+		let mut ns = Namespace::new();
+		let (ident, pair) = (ns.to_int("ident"), ns.to_int("pair"));
+		let (s, x, y) = (ns.to_int("s"), ns.to_int("x"), ns.to_int("y"));
+		let (tree, mut spans) = to_tree_spanned(Tokenizer::new("a\n  b c".chars()), &mut ns);
+		let rules = vec![Rule::new(
+			Sequence(s, vec![Group(ident, vec![MatchPoint(x)]), Group(ident, vec![MatchPoint(y)])]),
+			Sequence(s, vec![Group(pair, vec![Group(ident, vec![MatchPoint(y)]), MatchPoint(x)])]),
+		)];
+		let (result, iters) = run_tracked(&tree, &rules, &mut spans);
+		assert_eq!(iters, 1);
+		let leaf = |ns: &mut Namespace, name| Group(ident, vec![Atom(ns.to_int(name))]);
+		let (a, b, c) = (Atom(ns.to_int("a")), leaf(&mut ns, "b"), leaf(&mut ns, "c"));
+		assert_eq!(result, Group(ns.to_int("document"), vec![Group(pair, vec![b, a]), c]));
+
+		// document[pair[ident[b], a], ident[c]]
+		assert!(!spans.contains_key(&vec![0]));
+		assert!(!spans.contains_key(&vec![0, 0]));
+		assert_eq!(spans.get(&vec![0, 0, 0]).map(|sp| (sp.start.line, sp.start.column)), Some((2, 3)));
+		assert_eq!(spans.get(&vec![0, 1]).map(|sp| (sp.start.offset, sp.end.offset)), Some((0, 1)));
+		assert_eq!(spans.get(&vec![1]).map(|sp| (sp.start.line, sp.start.column, sp.end.column)), Some((2, 5, 6)));
+	}
+}
@@ -1,4 +1,14 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::str;
+
 use super::*;
+use ctok::*;
+use ctree::*;
+use lint::*;
 use ns::*;
 
 #[allow(non_snake_case)]
@@ -14,20 +24,25 @@ pub fn make_ttr_rules(ns: &mut Namespace) -> RuleSet {
 	let _MatchPoint = ns.to_int("MatchPoint");
 	let _Group = ns.to_int("Group");
 	let _Sequence = ns.to_int("Sequence");
-	let _Child = ns.to_int("Child");
 	let _Children = ns.to_int("Children");
+	let _ChildEnd = ns.to_int("ChildEnd");
 	let _Conjunctor = ns.to_int("Conjunctor");
 	let _Disjunctor = ns.to_int("Disjunctor");
 	let _Negator = ns.to_int("Negator");
 	let _Rule = ns.to_int("Rule");
-	let _Rules = ns.to_int("Rules");
 	let _RuleSet = ns.to_int("RuleSet");
+	let _RuleEnd = ns.to_int("RuleEnd");
+	let _Constructive = ns.to_int("Constructive");
+	let _Pattern = ns.to_int("Pattern");
+	let _document = ns.to_int("document");
 
 	let _sequence = ns.to_int("sequence");
 	let _x = ns.to_int("x");
 	let _y = ns.to_int("y");
 	let _a = ns.to_int("a");
-	let _b = ns.to_int("b");
+	let _c = ns.to_int("c");
+	let _l = ns.to_int("l");
+	let _n = ns.to_int("n");
 
 	let __lang = ns.to_int("<");
 	let __rang = ns.to_int(">");
@@ -43,148 +58,1111 @@ pub fn make_ttr_rules(ns: &mut Namespace) -> RuleSet {
 	let __amp = ns.to_int("&");
 	let __empty = ns.to_int("");
 
-	// Atoms and MatchPoints
-	rules.push(Rule {
-		lhs: Sequence(_sequence, vec![Group(_string, vec![MatchPoint(_x)])]),
-		rhs: Sequence(_sequence, vec![Group(_Atom, vec![MatchPoint(_x)])]),
-	});
-	rules.push(Rule {
-		lhs: Sequence(_sequence, vec![Group(_oper, vec![Atom(__lang)]), Group(_ident, vec![MatchPoint(_x)]), Group(_oper, vec![Atom(__rang)])]),
-		rhs: Sequence(_sequence, vec![Group(_MatchPoint, vec![MatchPoint(_x)])]),
-	});
-	rules.push(Rule {
-		lhs: Sequence(_sequence, vec![Group(_oper, vec![Atom(__lang)]), Group(_oper, vec![Atom(__rang)])]),
-		rhs: Sequence(_sequence, vec![Group(_MatchPoint, vec![Atom(__empty)])]),
-	});
-
-	// Groups and Sequences
-	rules.push(Rule {
-		lhs: Sequence(_sequence, vec![Group(_Atom, vec![MatchPoint(_x)]), Group(_Children, vec![MatchPoint(_y)])]),
-		rhs: Sequence(_sequence, vec![Group(_Group, vec![MatchPoint(_x), Group(_Children, vec![MatchPoint(_y)])])]),
-	});
-	rules.push(Rule {
-		lhs: Sequence(_sequence, vec![Group(_ident, vec![MatchPoint(_x)]), Group(_Children, vec![MatchPoint(_y)])]),
-		rhs: Sequence(_sequence, vec![Group(_Group, vec![MatchPoint(_x), Group(_Children, vec![MatchPoint(_y)])])]),
-	});
-	rules.push(Rule {
-		lhs: Sequence(_sequence, vec![Group(_MatchPoint, vec![MatchPoint(_x)]), Group(_Children, vec![MatchPoint(_y)])]),
-		rhs: Sequence(_sequence, vec![Group(_Sequence, vec![MatchPoint(_x), Group(_Children, vec![MatchPoint(_y)])])]),
-	});
-	rules.push(Rule {
-		lhs: Sequence(_sequence, vec![Group(_oper, vec![Atom(__lpar)]), Group(_ident, vec![MatchPoint(_x)]), Group(_oper, vec![Atom(__rpar)]), Group(_Children, vec![MatchPoint(_y)])]),
-		rhs: Sequence(_sequence, vec![Group(_Sequence, vec![MatchPoint(_x), Group(_Children, vec![MatchPoint(_y)])])]),
-	});
-
-	// Disjunctors and Conjunctors
-	rules.push(Rule {
-		lhs: Sequence(_sequence, vec![Group(_oper, vec![Atom(__bar)]), Group(_Children, vec![MatchPoint(_x)])]),
-		rhs: Sequence(_sequence, vec![Group(_Disjunctor, vec![Group(_Children, vec![MatchPoint(_x)])])]),
-	});
-	rules.push(Rule {
-		lhs: Sequence(_sequence, vec![Group(_oper, vec![Atom(__amp)]), Group(_Children, vec![MatchPoint(_x)])]),
-		rhs: Sequence(_sequence, vec![Group(_Conjunctor, vec![Group(_Children, vec![MatchPoint(_x)])])]),
-	});
-
-	// TTR Group Templates with Arity
-	let templates = vec![
-		Group(_Atom, vec![MatchPoint(_x)]),
-		Group(_MatchPoint, vec![MatchPoint(_x)]),
-		Group(_Group, vec![MatchPoint(_x), MatchPoint(_y)]),
-		Group(_Sequence, vec![MatchPoint(_x), MatchPoint(_y)]),
-		Group(_Disjunctor, vec![MatchPoint(_x)]),
-		Group(_Conjunctor, vec![MatchPoint(_y)]),
-	];
+	// Lists are built flat: an open list is a Children group ending in a
+	// ChildEnd[] marker, and items are spliced in before the marker. When
+	// the list closes it's put under its head, and the marker is dropped
+	// right away, while the new node is still at the top of the document.
+	let open = Sequence(_c, vec![Group(_ChildEnd, vec![])]);
+	let closed = Sequence(_c, vec![]);
+	// Finished nodes are wrapped in Constructive[] if they may be the rhs of
+	// a Rule, or Pattern[] if they may only be matched against, so the rules
+	// below take any of them without a copy per kind.
+	let constructive = |node: Node| Group(_Constructive, vec![node]);
+	let pattern = |node: Node| Group(_Pattern, vec![node]);
+	let any = Disjunctor(vec![constructive(MatchPoint(_x)), pattern(MatchPoint(_x))]);
 
-	// Negators
-	for template in &templates {
-		rules.push(Rule {
-			lhs: Sequence(_sequence, vec![Group(_oper, vec![Atom(__exclm)]), template.clone()]),
-			rhs: Sequence(_sequence, vec![Group(_Negator, vec![template.clone()])]),
-		});
-	}
+	// Finished lists
+	rules.push(Rule::new(
+		Sequence(_sequence, vec![constructive(Group(_Group, vec![MatchPoint(_x), open.clone()]))]),
+		Sequence(_sequence, vec![constructive(Group(_Group, vec![MatchPoint(_x), closed.clone()]))]),
+	));
+	rules.push(Rule::new(
+		Sequence(_sequence, vec![constructive(Group(_Sequence, vec![MatchPoint(_x), open.clone()]))]),
+		Sequence(_sequence, vec![constructive(Group(_Sequence, vec![MatchPoint(_x), closed.clone()]))]),
+	));
+	rules.push(Rule::new(
+		Sequence(_sequence, vec![pattern(Group(_Disjunctor, vec![open.clone()]))]),
+		Sequence(_sequence, vec![pattern(Group(_Disjunctor, vec![closed.clone()]))]),
+	));
+	rules.push(Rule::new(
+		Sequence(_sequence, vec![pattern(Group(_Conjunctor, vec![open.clone()]))]),
+		Sequence(_sequence, vec![pattern(Group(_Conjunctor, vec![closed.clone()]))]),
+	));
+
+	// Atoms and MatchPoints
+	// A string or match point followed by '[' or an open list is the head of
+	// a list that has yet to close, so the node after it is checked (and kept
+	// as is). Everything else is only built once it's complete.
+	let ahead = Conjunctor(vec![MatchPoint(_n), Negator(Box::new(Disjunctor(vec![Group(_oper, vec![Atom(__lbra)]), open.clone()])))]);
+	rules.push(Rule::new(
+		Sequence(_sequence, vec![Group(_string, vec![MatchPoint(_x)]), ahead.clone()]),
+		Sequence(_sequence, vec![constructive(Group(_Atom, vec![MatchPoint(_x)])), MatchPoint(_n)]),
+	));
+	rules.push(Rule::new(
+		Sequence(_sequence, vec![Group(_oper, vec![Atom(__lang)]), Group(_ident, vec![MatchPoint(_x)]), Group(_oper, vec![Atom(__rang)]), ahead.clone()]),
+		Sequence(_sequence, vec![constructive(Group(_MatchPoint, vec![MatchPoint(_x)])), MatchPoint(_n)]),
+	));
+	rules.push(Rule::new(
+		Sequence(_sequence, vec![Group(_oper, vec![Atom(__lang)]), Group(_oper, vec![Atom(__rang)]), ahead.clone()]),
+		Sequence(_sequence, vec![constructive(Group(_MatchPoint, vec![Atom(__empty)])), MatchPoint(_n)]),
+	));
 
 	// Children
+	// - Empty lists
+	rules.push(Rule::new(
+		Sequence(_sequence, vec![Group(_oper, vec![Atom(__lbra)]), Group(_oper, vec![Atom(__rbra)])]),
+		Sequence(_sequence, vec![Group(_Children, vec![Group(_ChildEnd, vec![])]), Group(_oper, vec![Atom(__rbra)])]),
+	));
 	// - Initiators
-	for template in &templates {
-		rules.push(Rule {
-			lhs: Sequence(_sequence, vec![Group(_oper, vec![Atom(__lbra)]), template.clone()]),
-			rhs: Sequence(_sequence, vec![Group(_Child, vec![template.clone()])]),
-		});
-	}
-	// - Continuations, arity 1
-	for template in &templates {
-		rules.push(Rule {
-			lhs: Sequence(_sequence, vec![Group(_Child, vec![MatchPoint(_a)]), Group(_oper, vec![Atom(__comma)]), template.clone()]),
-			rhs: Sequence(_sequence, vec![Group(_Child, vec![MatchPoint(_a), template.clone()])]),
-		});
-	}
-	// - Continuations, arity 2
-	for template in &templates {
-		rules.push(Rule {
-			lhs: Sequence(_sequence, vec![Group(_Child, vec![MatchPoint(_a), MatchPoint(_b)]), Group(_oper, vec![Atom(__comma)]), template.clone()]),
-			rhs: Sequence(_sequence, vec![Group(_Child, vec![Group(_Child, vec![MatchPoint(_a), MatchPoint(_b)]), template.clone()])]),
-		});
-	}
-	// - Terminators
-	rules.push(Rule {
-		lhs: Sequence(_sequence, vec![Group(_Child, vec![MatchPoint(_a)]), Group(_oper, vec![Atom(__rbra)])]),
-		rhs: Sequence(_sequence, vec![Group(_Children, vec![Group(_Child, vec![MatchPoint(_a)])])]),
-	});
-	rules.push(Rule {
-		lhs: Sequence(_sequence, vec![Group(_Child, vec![MatchPoint(_a), MatchPoint(_b)]), Group(_oper, vec![Atom(__rbra)])]),
-		rhs: Sequence(_sequence, vec![Group(_Children, vec![Group(_Child, vec![MatchPoint(_a), MatchPoint(_b)])])]),
-	});
-
-	// Rules
-	rules.push(Rule {
-		lhs: Sequence(_sequence, vec![Group(_Atom, vec![MatchPoint(_x)]), Group(_oper, vec![Atom(__dash)]), Group(_oper, vec![Atom(__rang)]), Group(_Atom, vec![MatchPoint(_a)])]),
-		rhs: Sequence(_sequence, vec![Group(_Rule, vec![Group(_Atom, vec![MatchPoint(_x)]), Group(_Atom, vec![MatchPoint(_a)])])]),
-	});
-	rules.push(Rule {
-		lhs: Sequence(_sequence, vec![Group(_Atom, vec![MatchPoint(_x)]), Group(_oper, vec![Atom(__dash)]), Group(_oper, vec![Atom(__rang)]), Group(_Group, vec![MatchPoint(_a), MatchPoint(_b)])]),
-		rhs: Sequence(_sequence, vec![Group(_Rule, vec![Group(_Atom, vec![MatchPoint(_x)]), Group(_Group, vec![MatchPoint(_a), MatchPoint(_b)])])]),
-	});
-	rules.push(Rule {
-		lhs: Sequence(_sequence, vec![Group(_Group, vec![MatchPoint(_x), MatchPoint(_y)]), Group(_oper, vec![Atom(__dash)]), Group(_oper, vec![Atom(__rang)]), Group(_Atom, vec![MatchPoint(_a)])]),
-		rhs: Sequence(_sequence, vec![Group(_Rule, vec![Group(_Group, vec![MatchPoint(_x), MatchPoint(_y)]), Group(_Atom, vec![MatchPoint(_a)])])]),
-	});
-	rules.push(Rule {
-		lhs: Sequence(_sequence, vec![Group(_Group, vec![MatchPoint(_x), MatchPoint(_y)]), Group(_oper, vec![Atom(__dash)]), Group(_oper, vec![Atom(__rang)]), Group(_Group, vec![MatchPoint(_a), MatchPoint(_b)])]),
-		rhs: Sequence(_sequence, vec![Group(_Rule, vec![Group(_Group, vec![MatchPoint(_x), MatchPoint(_y)]), Group(_Group, vec![MatchPoint(_a), MatchPoint(_b)])])]),
-	});
-	rules.push(Rule {
-		lhs: Sequence(_sequence, vec![Group(_Sequence, vec![MatchPoint(_x), MatchPoint(_y)]), Group(_oper, vec![Atom(__dash)]), Group(_oper, vec![Atom(__rang)]), Group(_Sequence, vec![MatchPoint(_a), MatchPoint(_b)])]),
-		rhs: Sequence(_sequence, vec![Group(_Rule, vec![Group(_Sequence, vec![MatchPoint(_x), MatchPoint(_y)]), Group(_Sequence, vec![MatchPoint(_a), MatchPoint(_b)])])]),
-	});
-
-	// Ruleset
-	rules.push(Rule {
-		lhs: Sequence(_sequence, vec![Group(_Rule, vec![MatchPoint(_x), MatchPoint(_y)]), Group(_oper, vec![Atom(__scol)])]),
-		rhs: Sequence(_sequence, vec![Group(_RuleSet, vec![Group(_Rules, vec![Group(_Rule, vec![MatchPoint(_x), MatchPoint(_y)])])])]),
-	});
-	rules.push(Rule {
-		lhs: Sequence(_sequence, vec![Group(_RuleSet, vec![Group(_Rules, vec![MatchPoint(_a)])]), Group(_Rule, vec![MatchPoint(_x), MatchPoint(_y)]), Group(_oper, vec![Atom(__scol)])]),
-		rhs: Sequence(_sequence, vec![Group(_RuleSet, vec![Group(_Rules, vec![MatchPoint(_a), Group(_Rule, vec![MatchPoint(_x), MatchPoint(_y)])])])]),
-	});
-	rules.push(Rule {
-		lhs: Sequence(_sequence, vec![Group(_RuleSet, vec![Group(_Rules, vec![MatchPoint(_a), MatchPoint(_b)])]), Group(_Rule, vec![MatchPoint(_x), MatchPoint(_y)]), Group(_oper, vec![Atom(__scol)])]),
-		rhs: Sequence(_sequence, vec![Group(_RuleSet, vec![Group(_Rules, vec![Group(_Rules, vec![MatchPoint(_a), MatchPoint(_b)]), Group(_Rule, vec![MatchPoint(_x), MatchPoint(_y)])])])]),
-	});
-	rules.push(Rule {
-		lhs: Sequence(_sequence, vec![Group(_RuleSet, vec![MatchPoint(_x)]), Group(_RuleSet, vec![MatchPoint(_y)])]),
-		rhs: Sequence(_sequence, vec![Group(_RuleSet, vec![Group(_Rules, vec![MatchPoint(_x), MatchPoint(_y)])])]),
-	});
+	rules.push(Rule::new(
+		Sequence(_sequence, vec![Group(_oper, vec![Atom(__lbra)]), any.clone()]),
+		Sequence(_sequence, vec![Group(_Children, vec![MatchPoint(_x), Group(_ChildEnd, vec![])])]),
+	));
+	// - Continuations
+	rules.push(Rule::new(
+		Sequence(_sequence, vec![open.clone(), Group(_oper, vec![Atom(__comma)]), any.clone()]),
+		Sequence(_sequence, vec![Sequence(_c, vec![MatchPoint(_x), Group(_ChildEnd, vec![])])]),
+	));
+	// - Terminators, which also attach the list to its head
+	let list = Conjunctor(vec![MatchPoint(_l), open.clone()]);
+	let rbra = Group(_oper, vec![Atom(__rbra)]);
+	rules.push(Rule::new(
+		Sequence(_sequence, vec![Group(_string, vec![MatchPoint(_x)]), list.clone(), rbra.clone()]),
+		Sequence(_sequence, vec![constructive(Group(_Group, vec![MatchPoint(_x), MatchPoint(_l)]))]),
+	));
+	rules.push(Rule::new(
+		Sequence(_sequence, vec![Group(_ident, vec![MatchPoint(_x)]), list.clone(), rbra.clone()]),
+		Sequence(_sequence, vec![constructive(Group(_Group, vec![MatchPoint(_x), MatchPoint(_l)]))]),
+	));
+	rules.push(Rule::new(
+		Sequence(_sequence, vec![Group(_oper, vec![Atom(__lang)]), Group(_ident, vec![MatchPoint(_x)]), Group(_oper, vec![Atom(__rang)]), list.clone(), rbra.clone()]),
+		Sequence(_sequence, vec![constructive(Group(_Sequence, vec![MatchPoint(_x), MatchPoint(_l)]))]),
+	));
+	rules.push(Rule::new(
+		Sequence(_sequence, vec![Group(_oper, vec![Atom(__lang)]), Group(_oper, vec![Atom(__rang)]), list.clone(), rbra.clone()]),
+		Sequence(_sequence, vec![constructive(Group(_Sequence, vec![Atom(__empty), MatchPoint(_l)]))]),
+	));
+	rules.push(Rule::new(
+		Sequence(_sequence, vec![Group(_oper, vec![Atom(__lpar)]), Group(_ident, vec![MatchPoint(_x)]), Group(_oper, vec![Atom(__rpar)]), list.clone(), rbra.clone()]),
+		Sequence(_sequence, vec![constructive(Group(_Sequence, vec![MatchPoint(_x), MatchPoint(_l)]))]),
+	));
+	rules.push(Rule::new(
+		Sequence(_sequence, vec![Group(_oper, vec![Atom(__bar)]), list.clone(), rbra.clone()]),
+		Sequence(_sequence, vec![pattern(Group(_Disjunctor, vec![MatchPoint(_l)]))]),
+	));
+	rules.push(Rule::new(
+		Sequence(_sequence, vec![Group(_oper, vec![Atom(__amp)]), list.clone(), rbra.clone()]),
+		Sequence(_sequence, vec![pattern(Group(_Conjunctor, vec![MatchPoint(_l)]))]),
+	));
+
+	// Negators
+	rules.push(Rule::new(
+		Sequence(_sequence, vec![Group(_oper, vec![Atom(__exclm)]), any.clone()]),
+		Sequence(_sequence, vec![pattern(Group(_Negator, vec![MatchPoint(_x)]))]),
+	));
+
+	// Rules: any pattern on the lhs, any constructive node on the rhs
+	rules.push(Rule::new(
+		Sequence(_sequence, vec![any, Group(_oper, vec![Atom(__dash)]), Group(_oper, vec![Atom(__rang)]), constructive(MatchPoint(_a))]),
+		Sequence(_sequence, vec![Group(_Rule, vec![MatchPoint(_x), MatchPoint(_a)])]),
+	));
+
+	// Ruleset, kept open with a RuleEnd[] marker until the document holds
+	// nothing else
+	rules.push(Rule::new(
+		Sequence(_sequence, vec![Sequence(_c, vec![Group(_RuleEnd, vec![])]), Group(_Rule, vec![MatchPoint(_x), MatchPoint(_y)]), Group(_oper, vec![Atom(__scol)])]),
+		Sequence(_sequence, vec![Sequence(_c, vec![Group(_Rule, vec![MatchPoint(_x), MatchPoint(_y)]), Group(_RuleEnd, vec![])])]),
+	));
+	rules.push(Rule::new(
+		Sequence(_sequence, vec![Group(_Rule, vec![MatchPoint(_x), MatchPoint(_y)]), Group(_oper, vec![Atom(__scol)])]),
+		Sequence(_sequence, vec![Group(_RuleSet, vec![Group(_Rule, vec![MatchPoint(_x), MatchPoint(_y)]), Group(_RuleEnd, vec![])])]),
+	));
+	rules.push(Rule::new(
+		Group(_document, vec![Sequence(_c, vec![Group(_RuleEnd, vec![])])]),
+		Group(_document, vec![closed]),
+	));
 	rules
 }
 
+// Nodes in error messages, as s-expressions where every id has a name.
+fn show(node: &Node, ns: &Namespace) -> String {
+	sexp::write(node, ns).unwrap_or_else(|_| format!("{:?}", node))
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReifyError {
+	pub expected: &'static str,
+	pub found: String,
+}
+
+impl fmt::Display for ReifyError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "Expected {} but found {}", self.expected, self.found)
+	}
+}
+
+struct Reifier<'n> {
+	ns: &'n Namespace,
+	document: usize,
+	atom: usize,
+	matchpoint: usize,
+	group: usize,
+	sequence: usize,
+	children: usize,
+	conjunctor: usize,
+	disjunctor: usize,
+	negator: usize,
+	rule: usize,
+	ruleset: usize,
+}
+
+impl<'n> Reifier<'n> {
+	fn error<T>(&self, expected: &'static str, found: &Node) -> Result<T, ReifyError> {
+		Err(ReifyError { expected, found: show(found, self.ns) })
+	}
+
+	fn rule(&self, node: &Node) -> Result<Rule, ReifyError> {
+		match *node {
+			Group(name, ref kids) if name == self.rule && kids.len() == 2 => {
+				Ok(Rule::new(self.pattern(&kids[0])?, self.pattern(&kids[1])?))
+			},
+			_ => self.error("a rule", node),
+		}
+	}
+
+	fn ruleset(&self, node: &Node, out: &mut RuleSet) -> Result<(), ReifyError> {
+		match *node {
+			Group(name, ref kids) if name == self.ruleset => {
+				for kid in kids {
+					out.push(self.rule(kid)?);
+				}
+			},
+			_ => out.push(self.rule(node)?),
+		}
+		Ok(())
+	}
+
+	fn children(&self, node: &Node) -> Result<Vec<Node>, ReifyError> {
+		match *node {
+			Group(name, ref kids) if name == self.children => kids.iter().map(|kid| self.pattern(kid)).collect(),
+			_ => self.error("a child list", node),
+		}
+	}
+
+	fn pattern(&self, node: &Node) -> Result<Node, ReifyError> {
+		let (name, kids) = match *node {
+			Group(name, ref kids) => (name, kids.as_slice()),
+			_ => return self.error("a pattern", node),
+		};
+		match kids {
+			[Atom(val)] if name == self.atom => Ok(Atom(*val)),
+			[Atom(val)] if name == self.matchpoint => Ok(MatchPoint(*val)),
+			[Atom(val), list] if name == self.group => Ok(Group(*val, self.children(list)?)),
+			[Atom(val), list] if name == self.sequence => Ok(Sequence(*val, self.children(list)?)),
+			[list] if name == self.conjunctor => Ok(Conjunctor(self.children(list)?)),
+			[list] if name == self.disjunctor => Ok(Disjunctor(self.children(list)?)),
+			[inner] if name == self.negator => Ok(Negator(Box::new(self.pattern(inner)?))),
+			_ => self.error("a pattern", node),
+		}
+	}
+}
+
+// Turns the TT tree built by make_ttr_rules (a document of RuleSet, Rules and
+// Rule groups) into rules that can be run.
+pub fn reify(tree: &Node, ns: &mut Namespace) -> Result<RuleSet, ReifyError> {
+	let reifier = Reifier {
+		document: ns.to_int("document"),
+		atom: ns.to_int("Atom"),
+		matchpoint: ns.to_int("MatchPoint"),
+		group: ns.to_int("Group"),
+		sequence: ns.to_int("Sequence"),
+		children: ns.to_int("Children"),
+		conjunctor: ns.to_int("Conjunctor"),
+		disjunctor: ns.to_int("Disjunctor"),
+		negator: ns.to_int("Negator"),
+		rule: ns.to_int("Rule"),
+		ruleset: ns.to_int("RuleSet"),
+		ns,
+	};
+	let mut rules = RuleSet::new();
+	match *tree {
+		// Only a finished RuleSet counts; a Rule left lying in the document
+		// is missing its ';'.
+		Group(name, ref kids) if name == reifier.document => {
+			for kid in kids {
+				match *kid {
+					Group(name, _) if name == reifier.ruleset => reifier.ruleset(kid, &mut rules)?,
+					_ => return reifier.error("a rule ending in ';'", kid),
+				}
+			}
+		},
+		_ => reifier.ruleset(tree, &mut rules)?,
+	}
+	Ok(rules)
+}
+
+// Parses TT source with the given grammar and reifies the result.
+pub fn parse_tt(text: &str, grammar: &RuleSet, ns: &mut Namespace) -> Result<RuleSet, ReifyError> {
+	let tree = to_tree(Tokenizer::from_text(text), ns);
+	let (tree, _) = run(&tree, grammar);
+	reify(&tree, ns)
+}
+
+// Parses TT source with the bootstrap rules and reifies the result.
+pub fn load_tt(text: &str, ns: &mut Namespace) -> Result<RuleSet, ReifyError> {
+	let bootstrap = make_ttr_rules(ns);
+	parse_tt(text, &bootstrap, ns)
+}
+
+// The self-hosting check for a grammar written in TT (i.e. ttr.tt): the
+// first generation is that source parsed with make_ttr_rules, the second the
+// same source parsed with the first. Both should equal make_ttr_rules; see
+// diff_rulesets.
+pub fn bootstrap(text: &str, ns: &mut Namespace) -> Result<(RuleSet, RuleSet), ReifyError> {
+	let gen1 = load_tt(text, ns)?;
+	let gen2 = parse_tt(text, &gen1, ns)?;
+	Ok((gen1, gen2))
+}
+
+fn node_diff(a: &Node, b: &Node, path: &mut Vec<usize>, ns: &Namespace, out: &mut Vec<String>) {
+	let same_shape = match (a, b) {
+		(Group(x, av), Group(y, bv)) | (Sequence(x, av), Sequence(y, bv)) => x == y && av.len() == bv.len(),
+		(Conjunctor(av), Conjunctor(bv)) | (Disjunctor(av), Disjunctor(bv)) => av.len() == bv.len(),
+		(Negator(_), Negator(_)) => true,
+		_ => a == b,
+	};
+	if !same_shape {
+		out.push(format!("at {:?}: {} != {}", path, show(a, ns), show(b, ns)));
+		return;
+	}
+	let kids = match (a, b) {
+		(Group(_, av), Group(_, bv)) | (Sequence(_, av), Sequence(_, bv)) |
+		(Conjunctor(av), Conjunctor(bv)) | (Disjunctor(av), Disjunctor(bv)) => av.iter().zip(bv.iter()).collect(),
+		(Negator(ai), Negator(bi)) => vec![(&**ai, &**bi)],
+		_ => Vec::new(),
+	};
+	for (i, (ak, bk)) in kids.into_iter().enumerate() {
+		path.push(i);
+		node_diff(ak, bk, path, ns, out);
+		path.pop();
+	}
+}
+
+// Lists every structural difference between two rule sets, one line each,
+// naming the rule, side and child path where they part ways.
+pub fn diff_rulesets(a: &RuleSet, b: &RuleSet, ns: &Namespace) -> Vec<String> {
+	let mut out = Vec::new();
+	if a.len() != b.len() {
+		out.push(format!("{} rules != {} rules", a.len(), b.len()));
+	}
+	for (i, (ra, rb)) in a.iter().zip(b.iter()).enumerate() {
+		let mut lines = Vec::new();
+		node_diff(&ra.lhs, &rb.lhs, &mut Vec::new(), ns, &mut lines);
+		out.extend(lines.drain(..).map(|l| format!("rule {} lhs {}", i, l)));
+		node_diff(&ra.rhs, &rb.rhs, &mut Vec::new(), ns, &mut lines);
+		out.extend(lines.drain(..).map(|l| format!("rule {} rhs {}", i, l)));
+	}
+	out
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParseError {
+	pub pos: Position,
+	pub msg: String,
+}
+
+impl fmt::Display for ParseError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "{}:{}: {}", self.pos.line, self.pos.column, self.msg)
+	}
+}
+
+struct Parser<'a, 'n, T: Iterator<Item=char>> {
+	toks: TokenStream<'a, T>,
+	ns: &'n mut Namespace,
+	file: Option<String>,
+	// Macro variables in scope and the template lists named by @let
+	vars: Vec<String>,
+	lists: HashMap<String, Vec<Node>>,
+}
+
+impl<'a, 'n> Parser<'a, 'n, str::Chars<'a>> {
+	fn new(text: &'a str, ns: &'n mut Namespace, file: Option<String>) -> Self {
+		Parser { toks: Tokenizer::from_text(text).stream(), ns, file, vars: Vec::new(), lists: HashMap::new() }
+	}
+}
+
+impl<'a, 'n, T: Iterator<Item=char>> Parser<'a, 'n, T> {
+	fn error<R>(&self, pos: Position, msg: &str) -> Result<R, ParseError> {
+		Err(ParseError { pos, msg: msg.to_string() })
+	}
+
+	// The next token and where it starts; EOF sits at the end of the input.
+	fn peek(&mut self) -> Result<(Token, Position), ParseError> {
+		match self.toks.peek_spanned(0).cloned() {
+			Some((Token::Error(e), _)) => Err(ParseError { pos: e.pos, msg: e.kind.to_string() }),
+			Some((tok, span)) => Ok((tok, span.start)),
+			None => Ok((Token::EOF, self.toks.tokenizer().position())),
+		}
+	}
+
+	fn next(&mut self) -> Result<(Token, Position), ParseError> {
+		let next = self.peek()?;
+		self.toks.next();
+		Ok(next)
+	}
+
+	fn at_oper(&mut self, c: char) -> Result<bool, ParseError> {
+		Ok(self.peek()?.0 == Token::OPER(c))
+	}
+
+	fn expect_oper(&mut self, c: char) -> Result<(), ParseError> {
+		match self.next()? {
+			(Token::OPER(o), _) if o == c => Ok(()),
+			(_, pos) => self.error(pos, &format!("Expected '{}'", c)),
+		}
+	}
+
+	fn expect_ident(&mut self, what: &str) -> Result<usize, ParseError> {
+		match self.next()? {
+			(Token::IDENT(s), _) => Ok(self.ns.to_int(&s)),
+			(_, pos) => self.error(pos, &format!("Expected {}", what)),
+		}
+	}
+
+	fn children(&mut self) -> Result<Vec<Node>, ParseError> {
+		self.expect_oper('[')?;
+		if self.at_oper(']')? {
+			self.next()?;
+			return Ok(Vec::new());
+		}
+		let mut children = vec![self.template()?];
+		while self.at_oper(',')? {
+			self.next()?;
+			children.push(self.template()?);
+		}
+		match self.next()? {
+			(Token::OPER(']'), _) => Ok(children),
+			(_, pos) => self.error(pos, "Expected ',' or ']'"),
+		}
+	}
+
+	fn template(&mut self) -> Result<Node, ParseError> {
+		let (tok, pos) = self.next()?;
+		match tok {
+			Token::STRING(s) => {
+				let val = self.ns.to_int(&s);
+				if self.at_oper('[')? {
+					Ok(Group(val, self.children()?))
+				} else {
+					Ok(Atom(val))
+				}
+			},
+			Token::IDENT(s) => {
+				let val = self.ns.to_int(&s);
+				if !self.at_oper('[')? {
+					let at = self.peek()?.1;
+					return self.error(at, "Expected '[' after a group name");
+				}
+				Ok(Group(val, self.children()?))
+			},
+			Token::OPER('<') => {
+				let val = if self.at_oper('>')? { self.ns.to_int("") } else { self.expect_ident("a match point name")? };
+				self.expect_oper('>')?;
+				if self.at_oper('[')? {
+					Ok(Sequence(val, self.children()?))
+				} else {
+					Ok(MatchPoint(val))
+				}
+			},
+			Token::OPER('(') => {
+				let val = self.expect_ident("a sequence name")?;
+				self.expect_oper(')')?;
+				Ok(Sequence(val, self.children()?))
+			},
+			Token::OPER('|') => Ok(Disjunctor(self.children()?)),
+			Token::OPER('&') => Ok(Conjunctor(self.children()?)),
+			Token::OPER('!') => Ok(Negator(Box::new(self.template()?))),
+			// Macro variables stand in as match points until @for substitutes them
+			Token::OPER('$') => match self.next()? {
+				(Token::IDENT(ref s), _) if self.vars.contains(s) => Ok(MatchPoint(self.ns.to_int(&format!("${}", s)))),
+				(_, pos) => self.error(pos, "Expected a macro variable bound by @for"),
+			},
+			_ => self.error(pos, "Expected a pattern"),
+		}
+	}
+
+	// [key, key = "value", ...] before a rule.
+	fn attributes(&mut self) -> Result<Vec<(String, Option<String>)>, ParseError> {
+		let mut attrs = Vec::new();
+		self.expect_oper('[')?;
+		loop {
+			let key = match self.next()? {
+				(Token::IDENT(s), _) => s,
+				(_, pos) => return self.error(pos, "Expected an attribute name"),
+			};
+			let value = if self.at_oper('=')? {
+				self.next()?;
+				match self.next()? {
+					(Token::STRING(s), _) => Some(s),
+					(_, pos) => return self.error(pos, "Expected a quoted attribute value"),
+				}
+			} else { None };
+			attrs.push((key, value));
+			match self.next()? {
+				(Token::OPER(','), _) => (),
+				(Token::OPER(']'), _) => return Ok(attrs),
+				(_, pos) => return self.error(pos, "Expected ',' or ']'"),
+			}
+		}
+	}
+
+	// [attributes] name: lhs -> rhs;  with the attributes and name optional.
+	fn rule(&mut self) -> Result<Rule, ParseError> {
+		let start = self.peek()?.1;
+		let attributes = if self.at_oper('[')? { self.attributes()? } else { Vec::new() };
+		let colon = self.toks.peek_spanned(1).map(|t| &t.0) == Some(&Token::OPER(':'));
+		let name = match self.peek()?.0 {
+			Token::IDENT(s) if colon => Some(s),
+			_ => None,
+		};
+		if name.is_some() {
+			let (_, at) = self.next()?;
+			// Every expansion would share the name, so nothing could tell them apart
+			if !self.vars.is_empty() {
+				return self.error(at, "Rules inside @for can't be named");
+			}
+			self.next()?;
+		}
+		let lhs = self.template()?;
+		self.expect_oper('-')?;
+		self.expect_oper('>')?;
+		let pos = self.peek()?.1;
+		let rhs = self.template()?;
+		match rhs {
+			Atom(_) | MatchPoint(_) | Group(..) | Sequence(..) => (),
+			_ => return self.error(pos, "The rhs of a rule must be an atom, match point, group or sequence"),
+		}
+		self.expect_oper(';')?;
+		let mut rule = Rule::new(lhs, rhs);
+		rule.meta.enabled = !attributes.iter().any(|(k, _)| k == "disabled");
+		rule.meta.name = name;
+		rule.meta.attributes = attributes;
+		rule.meta.location = Some(Location { file: self.file.clone(), line: start.line, column: start.column });
+		Ok(rule)
+	}
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Placement {
+	Prepend,
+	Inline,
+	Append,
+}
+
+enum Item {
+	Rule(Rule),
+	Include(Placement, String, Position),
+}
+
+// Replaces every use of the macro variable `var` in a template.
+fn substitute(node: &Node, var: usize, value: &Node) -> Node {
+	let all = |v: &Vec<Node>| v.iter().map(|n| substitute(n, var, value)).collect();
+	match *node {
+		MatchPoint(idx) if idx == var => value.clone(),
+		Group(name, ref v) => Group(name, all(v)),
+		Sequence(idx, ref v) => Sequence(idx, all(v)),
+		Conjunctor(ref v) => Conjunctor(all(v)),
+		Disjunctor(ref v) => Disjunctor(all(v)),
+		Negator(ref n) => Negator(Box::new(substitute(n, var, value))),
+		_ => node.clone(),
+	}
+}
+
+impl<'a, 'n, T: Iterator<Item=char>> Parser<'a, 'n, T> {
+	// @include [prepend|append] "path";
+	fn include(&mut self, pos: Position) -> Result<Item, ParseError> {
+		let placement = match self.peek()? {
+			(Token::IDENT(ref s), _) if s == "prepend" => Placement::Prepend,
+			(Token::IDENT(ref s), _) if s == "append" => Placement::Append,
+			_ => Placement::Inline,
+		};
+		if placement != Placement::Inline {
+			self.next()?;
+		}
+		let path = match self.next()? {
+			(Token::STRING(s), _) => s,
+			(_, pos) => return self.error(pos, "Expected a quoted path to include"),
+		};
+		self.expect_oper(';')?;
+		Ok(Item::Include(placement, path, pos))
+	}
+
+	// A list of templates for @for, either written out or named by @let.
+	fn template_list(&mut self) -> Result<Vec<Node>, ParseError> {
+		match self.peek()? {
+			(Token::IDENT(name), pos) => {
+				self.next()?;
+				match self.lists.get(&name) {
+					Some(list) => Ok(list.clone()),
+					None => self.error(pos, &format!("Unknown template list {}", name)),
+				}
+			},
+			_ => self.children(),
+		}
+	}
+
+	// @let name = [templates];
+	fn let_list(&mut self) -> Result<(), ParseError> {
+		let name = match self.next()? {
+			(Token::IDENT(s), _) => s,
+			(_, pos) => return self.error(pos, "Expected a name for the template list"),
+		};
+		self.expect_oper('=')?;
+		let list = self.template_list()?;
+		self.expect_oper(';')?;
+		self.lists.insert(name, list);
+		Ok(())
+	}
+
+	// @for $var in list { rules }, which stamps out the rules once for each
+	// template in the list, in order. The rules may carry attributes but not
+	// names, which would be repeated on every copy.
+	fn for_loop(&mut self, pos: Position) -> Result<Vec<Item>, ParseError> {
+		self.expect_oper('$')?;
+		let var = match self.next()? {
+			(Token::IDENT(s), _) => s,
+			(_, pos) => return self.error(pos, "Expected a macro variable name"),
+		};
+		match self.next()? {
+			(Token::IDENT(ref s), _) if s == "in" => (),
+			(_, pos) => return self.error(pos, "Expected 'in'"),
+		}
+		let list = self.template_list()?;
+		self.expect_oper('{')?;
+		self.vars.push(var.clone());
+		let body = self.items(Some('}'));
+		self.vars.pop();
+		let body = body?;
+		self.expect_oper('}')?;
+		let placeholder = self.ns.to_int(&format!("${}", var));
+		let mut items = Vec::new();
+		for value in &list {
+			for item in &body {
+				match *item {
+					Item::Rule(ref rule) => {
+						let mut rule = rule.clone();
+						rule.lhs = substitute(&rule.lhs, placeholder, value);
+						rule.rhs = substitute(&rule.rhs, placeholder, value);
+						match rule.rhs {
+							Atom(_) | MatchPoint(_) | Group(..) | Sequence(..) => (),
+							_ => return self.error(pos, &format!("${} gives a rule whose rhs isn't an atom, match point, group or sequence", var)),
+						}
+						items.push(Item::Rule(rule));
+					},
+					Item::Include(_, _, pos) => return self.error(pos, "@include isn't allowed inside @for"),
+				}
+			}
+		}
+		Ok(items)
+	}
+
+	fn directive(&mut self, items: &mut Vec<Item>) -> Result<(), ParseError> {
+		let (_, pos) = self.next()?;
+		match self.next()? {
+			(Token::IDENT(ref s), _) if s == "include" => items.push(self.include(pos)?),
+			(Token::IDENT(ref s), _) if s == "let" => self.let_list()?,
+			(Token::IDENT(ref s), _) if s == "for" => items.extend(self.for_loop(pos)?),
+			(_, pos) => return self.error(pos, "Expected 'include', 'let' or 'for' after '@'"),
+		}
+		Ok(())
+	}
+
+	// Items up to the end of the input, or up to (not including) `close`.
+	fn items(&mut self, close: Option<char>) -> Result<Vec<Item>, ParseError> {
+		let mut items = Vec::new();
+		loop {
+			match self.peek()?.0 {
+				Token::EOF => break,
+				Token::OPER(c) if Some(c) == close => break,
+				Token::OPER('@') => self.directive(&mut items)?,
+				_ => items.push(Item::Rule(self.rule()?)),
+			}
+		}
+		Ok(items)
+	}
+}
+
+// Parses TT source straight into rules, accepting the language of load_tt
+// (the make_ttr_rules grammar) plus rule names and attributes and the @let
+// and @for macros, much faster and with the position of the first error.
+// @include needs a file to resolve against, so it's only accepted by
+// load_rules.
+pub fn parse_rules(text: &str, ns: &mut Namespace) -> Result<RuleSet, ParseError> {
+	let mut parser = Parser::new(text, ns, None);
+	let mut rules = RuleSet::new();
+	for item in parser.items(None)? {
+		match item {
+			Item::Rule(rule) => rules.push(rule),
+			Item::Include(_, _, pos) => return parser.error(pos, "@include is only allowed in files loaded with load_rules"),
+		}
+	}
+	Ok(rules)
+}
+
+#[derive(Debug)]
+pub enum LoadError {
+	Io(PathBuf, io::Error),
+	Parse(PathBuf, ParseError),
+	Cycle(Vec<PathBuf>),
+	// The lints that are errors, see LintKind::is_error
+	Lint(Vec<Lint>),
+}
+
+impl fmt::Display for LoadError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match *self {
+			LoadError::Io(ref path, ref err) => write!(f, "{}: {}", path.display(), err),
+			LoadError::Parse(ref path, ref err) => write!(f, "{}:{}", path.display(), err),
+			LoadError::Cycle(ref paths) => {
+				let names: Vec<String> = paths.iter().map(|p| p.display().to_string()).collect();
+				write!(f, "Include cycle: {}", names.join(" -> "))
+			},
+			LoadError::Lint(ref lints) => {
+				let lines: Vec<String> = lints.iter().map(Lint::to_string).collect();
+				write!(f, "{}", lines.join("\n"))
+			},
+		}
+	}
+}
+
+fn load_file(path: &Path, ns: &mut Namespace, stack: &mut Vec<PathBuf>) -> Result<RuleSet, LoadError> {
+	let canon = fs::canonicalize(path).map_err(|e| LoadError::Io(path.to_path_buf(), e))?;
+	if let Some(start) = stack.iter().position(|p| *p == canon) {
+		let mut cycle = stack[start..].to_vec();
+		cycle.push(canon);
+		return Err(LoadError::Cycle(cycle));
+	}
+	let text = fs::read_to_string(&canon).map_err(|e| LoadError::Io(path.to_path_buf(), e))?;
+	let items = Parser::new(&text, &mut *ns, Some(path.display().to_string())).items(None)
+		.map_err(|e| LoadError::Parse(path.to_path_buf(), e))?;
+	let dir = canon.parent().map(Path::to_path_buf).unwrap_or_default();
+	stack.push(canon);
+	let (mut before, mut rules, mut after) = (RuleSet::new(), RuleSet::new(), RuleSet::new());
+	for item in items {
+		match item {
+			Item::Rule(rule) => rules.push(rule),
+			Item::Include(placement, file, _) => {
+				let included = load_file(&dir.join(file), ns, stack)?;
+				match placement {
+					Placement::Prepend => before.extend(included),
+					Placement::Inline => rules.extend(included),
+					Placement::Append => after.extend(included),
+				}
+			},
+		}
+	}
+	stack.pop();
+	before.extend(rules);
+	before.extend(after);
+	Ok(before)
+}
+
+// Loads a TT file, resolving @include directives relative to the file that
+// contains them. A plain @include splices the included rules in where the
+// directive is; "prepend" and "append" put them before or after all of the
+// including file's own rules, in directive order.
+// The rules are then linted as a whole: lints that would make eval panic fail
+// the load, and the rest come back as warnings alongside the rules.
+pub fn load_rules<P: AsRef<Path>>(path: P, ns: &mut Namespace) -> Result<(RuleSet, Vec<Lint>), LoadError> {
+	let rules = load_file(path.as_ref(), ns, &mut Vec::new())?;
+	let (errors, warnings): (Vec<Lint>, Vec<Lint>) = lint(&rules, ns).into_iter().partition(|l| l.kind.is_error());
+	if !errors.is_empty() {
+		return Err(LoadError::Lint(errors));
+	}
+	Ok((rules, warnings))
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct DecompileError {
+	pub rule: String,
+	pub msg: String,
+}
+
+impl fmt::Display for DecompileError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "{}: {}", self.rule, self.msg)
+	}
+}
+
+fn is_ident(s: &str) -> bool {
+	let mut chars = s.chars();
+	match chars.next() {
+		Some(c) if c.is_ascii_alphabetic() || c == '_' => chars.all(|c| c.is_ascii_alphanumeric() || c == '_'),
+		_ => false,
+	}
+}
+
+fn quote(s: &str) -> String {
+	format!("'{}'", s.escape_default())
+}
+
+struct Writer<'n> {
+	ns: &'n Namespace,
+}
+
+impl<'n> Writer<'n> {
+	fn name(&self, val: usize) -> Result<&'n str, String> {
+		self.ns.to_str(val).map(String::as_str).ok_or_else(|| format!("{} isn't in the namespace", val))
+	}
+
+	// Match point and sequence names have to be written as identifiers (or
+	// left empty, as in <>).
+	fn var(&self, val: usize) -> Result<&'n str, String> {
+		match self.name(val)? {
+			name if name.is_empty() || is_ident(name) => Ok(name),
+			name => Err(format!("{:?} can't be written as a match point name", name)),
+		}
+	}
+
+	fn list(&self, kids: &[Node], out: &mut String) -> Result<(), String> {
+		out.push('[');
+		for (i, kid) in kids.iter().enumerate() {
+			if i > 0 { out.push_str(", "); }
+			self.template(kid, out)?;
+		}
+		out.push(']');
+		Ok(())
+	}
+
+	fn template(&self, node: &Node, out: &mut String) -> Result<(), String> {
+		match *node {
+			Atom(val) => out.push_str(&quote(self.name(val)?)),
+			Group(name, ref kids) => {
+				let name = self.name(name)?;
+				out.push_str(&if is_ident(name) { name.to_string() } else { quote(name) });
+				self.list(kids, out)?;
+			},
+			MatchPoint(val) => out.push_str(&format!("<{}>", self.var(val)?)),
+			Sequence(val, ref kids) => {
+				out.push_str(&format!("<{}>", self.var(val)?));
+				self.list(kids, out)?;
+			},
+			Conjunctor(ref kids) => {
+				out.push('&');
+				self.list(kids, out)?;
+			},
+			Disjunctor(ref kids) => {
+				out.push('|');
+				self.list(kids, out)?;
+			},
+			Negator(ref inner) => {
+				out.push('!');
+				self.template(inner, out)?;
+			},
+			SplicePair(..) | NoNode => return Err(format!("{:?} isn't a template", node)),
+		}
+		Ok(())
+	}
+
+	fn rule(&self, rule: &Rule, out: &mut String) -> Result<(), String> {
+		// The parser disables exactly the rules with a "disabled" attribute
+		let mut attrs = Vec::new();
+		if !rule.meta.enabled && rule.attribute("disabled").is_none() {
+			attrs.push("disabled".to_string());
+		}
+		for (k, v) in &rule.meta.attributes {
+			if !is_ident(k) {
+				return Err(format!("{:?} can't be written as an attribute name", k));
+			}
+			if k == "disabled" && rule.meta.enabled {
+				continue;
+			}
+			attrs.push(match *v {
+				Some(ref v) => format!("{} = \"{}\"", k, v.escape_default()),
+				None => k.clone(),
+			});
+		}
+		if !attrs.is_empty() {
+			out.push_str(&format!("[{}] ", attrs.join(", ")));
+		}
+		if let Some(ref name) = rule.meta.name {
+			if !is_ident(name) {
+				return Err(format!("{:?} can't be written as a rule name", name));
+			}
+			out.push_str(&format!("{}: ", name));
+		}
+		match rule.rhs {
+			Atom(_) | MatchPoint(_) | Group(..) | Sequence(..) => (),
+			_ => return Err("The rhs of a rule must be an atom, match point, group or sequence".to_string()),
+		}
+		self.template(&rule.lhs, out)?;
+		out.push_str(" -> ");
+		self.template(&rule.rhs, out)?;
+		out.push_str(";\n");
+		Ok(())
+	}
+}
+
+// Writes rules back out as TT source that parse_rules reads as the same rules,
+// one per line, with names and attributes but not source locations. Whether a
+// rule is enabled is carried by its "disabled" attribute, which is added or
+// dropped to match.
+pub fn decompile(rules: &RuleSet, ns: &Namespace) -> Result<String, DecompileError> {
+	let writer = Writer { ns };
+	let mut out = String::new();
+	for (i, rule) in rules.iter().enumerate() {
+		writer.rule(rule, &mut out).map_err(|msg| DecompileError { rule: rule.describe(i), msg })?;
+	}
+	Ok(out)
+}
+
 #[cfg(test)]
 mod tests {
 	use super::*;
-	use ns::*;
 
 	#[test]
 	fn disp_ttr_rules() {
 		let mut ns = Namespace::new();
 		println!("{:?}", make_ttr_rules(&mut ns));
 	}
+
+	#[test]
+	fn reify_tt_source() {
+		let mut ns = Namespace::new();
+		let rules = load_tt("
+			<s>[ident['a'], <x>] -> <s>[pair[<x>, 'b']];
+			<s>[|[<y>, 'c'], &[<z>]] -> <s>[<z>];
+		", &mut ns).unwrap();
+		let (s, x, y, z) = (ns.to_int("s"), ns.to_int("x"), ns.to_int("y"), ns.to_int("z"));
+		assert_eq!(rules.len(), 2);
+		assert_eq!(rules[0].lhs, Sequence(s, vec![Group(ns.to_int("ident"), vec![Atom(ns.to_int("a"))]), MatchPoint(x)]));
+		assert_eq!(rules[0].rhs, Sequence(s, vec![Group(ns.to_int("pair"), vec![MatchPoint(x), Atom(ns.to_int("b"))])]));
+		assert_eq!(rules[1].lhs, Sequence(s, vec![Disjunctor(vec![MatchPoint(y), Atom(ns.to_int("c"))]), Conjunctor(vec![MatchPoint(z)])]));
+		assert_eq!(rules[1].rhs, Sequence(s, vec![MatchPoint(z)]));
+
+		let err = load_tt("'a' -> ;", &mut ns).unwrap_err();
+		assert_eq!(err.expected, "a rule ending in ';'");
+		assert_eq!(err.found, "(Constructive (Atom \"a\"))");
+	}
+
+	#[test]
+	fn ttr_tt_is_self_hosting() {
+		let mut ns = Namespace::new();
+		let gen0 = make_ttr_rules(&mut ns);
+		let (gen1, gen2) = bootstrap(include_str!("../ttr.tt"), &mut ns).unwrap();
+		assert_eq!(diff_rulesets(&gen0, &gen1, &ns), Vec::<String>::new());
+		assert_eq!(diff_rulesets(&gen1, &gen2, &ns), Vec::<String>::new());
+
+		let mut altered = gen1.clone();
+		altered[3].rhs = Sequence(ns.to_int("sequence"), vec![Group(ns.to_int("Groop"), vec![])]);
+		altered.pop();
+		assert_eq!(diff_rulesets(&gen0, &altered, &ns), vec![
+			format!("{} rules != {} rules", gen0.len(), gen0.len() - 1),
+			"rule 3 rhs at [0]: (Pattern (Conjunctor (<c>))) != (Groop)".to_string(),
+		]);
+	}
+
+	#[test]
+	fn parser_agrees_with_bootstrap() {
+		let mut ns = Namespace::new();
+		// ttr_tt_is_self_hosting already checks load_tt on ttr.tt
+		let grammar = make_ttr_rules(&mut ns);
+		let direct = parse_rules(include_str!("../ttr.tt"), &mut ns).unwrap();
+		assert_eq!(diff_rulesets(&direct, &grammar, &ns), Vec::<String>::new());
+
+		let sources = [
+			"",
+			"'a' -> 'b'; 'c'['d'] -> e[<>, <f>];",
+			"<s>[|[<y>, 'c'], &[(z)['q']]] -> <s>[<z>, g[h['i']]];",
+			"'a' -> 'b'",
+			"<s>['a'] -> 'b';",
+			"<x> -> 'b';",
+			"a -> 'b';",
+			"'a'[] -> <s>[g[], <>[], |[], &[], !h[]];",
+			"'a'[<> -> 'b';",
+			"'a'['b'['c', 'd'], 'e'['f']] -> 'g'['h', <i>['j'], 'k'];",
+			"'a'['b',] -> 'b';",
+			"<s>[!'a', !!g['h'], !<y>[<z>]] -> <s>['b'];",
+			"!'g'['a'] -> <x>; |['a', <b>] -> 'c'; &[<x>, !'d'] -> (s)[<x>]; !<z> -> z[<z>];",
+			"'a' -> &['b'];",
+			"'a' -> !'b';",
+			"<'s'>['a'] -> <s>['b'];",
+			"'a' -> 'b'; ;",
+		];
+		for src in sources.iter() {
+			let direct = parse_rules(src, &mut ns);
+			let rewritten = load_tt(src, &mut ns);
+			assert_eq!(direct.is_ok(), rewritten.is_ok(), "{:?}: {:?} vs {:?}", src, direct, rewritten);
+			if let (Ok(a), Ok(b)) = (direct, rewritten) {
+				assert_eq!(diff_rulesets(&a, &b, &ns), Vec::<String>::new());
+			}
+		}
+	}
+
+	#[test]
+	fn parse_errors_have_positions() {
+		let mut ns = Namespace::new();
+		let err = |src: &str, ns: &mut Namespace| parse_rules(src, ns).unwrap_err().to_string();
+		assert_eq!(err("'a' -> 'b';\n<s>['a' 'b'] -> <s>[];", &mut ns), "2:9: Expected ',' or ']'");
+		assert_eq!(err("'a' -> |['b'];", &mut ns), "1:8: The rhs of a rule must be an atom, match point, group or sequence");
+		assert_eq!(err("'a' -> 'b'", &mut ns), "1:11: Expected ';'");
+		assert_eq!(err("'a' -> 'b;", &mut ns), "1:8: Unexpected EOF in string");
+	}
+
+	#[test]
+	fn flat_lists_of_any_arity() {
+		let mut ns = Namespace::new();
+		let (s, g) = (ns.to_int("s"), ns.to_int("g"));
+		for &n in &[0, 1, 2, 10] {
+			let items: Vec<String> = (0..n).map(|i| format!("'{}'", i)).collect();
+			let src = format!("<s>[g[{}]] -> <s>[g[{}], <>];", items.join(", "), items.join(", "));
+			let atoms: Vec<Node> = (0..n).map(|i| Atom(ns.to_int(&i.to_string()))).collect();
+			let expected = Rule::new(
+				Sequence(s, vec![Group(g, atoms.clone())]),
+				Sequence(s, vec![Group(g, atoms), MatchPoint(ns.to_int(""))]),
+			);
+			let rewritten = load_tt(&src, &mut ns).unwrap();
+			let direct = parse_rules(&src, &mut ns).unwrap();
+			assert_eq!(diff_rulesets(&rewritten, &vec![expected.clone()], &ns), Vec::<String>::new());
+			assert_eq!(diff_rulesets(&direct, &vec![expected], &ns), Vec::<String>::new());
+		}
+	}
+
+	#[test]
+	fn includes() {
+		let dir = std::env::temp_dir().join(format!("rtt-include-{}", std::process::id()));
+		fs::create_dir_all(dir.join("lib")).unwrap();
+		let write = |name: &str, text: &str| fs::write(dir.join(name), text).unwrap();
+		write("main.tt", "'m1' -> 'x'; @include append \"lib/last.tt\"; @include \"lib/mid.tt\"; 'm2' -> 'x'; @include prepend \"lib/first.tt\";");
+		write("lib/first.tt", "'f' -> 'x';");
+		write("lib/mid.tt", "'i1' -> 'x'; @include \"../lib/first.tt\"; 'i2' -> 'x';");
+		write("lib/last.tt", "'l' -> 'x';");
+		write("loop.tt", "'a' -> 'x'; @include \"lib/loop.tt\";");
+		write("lib/loop.tt", "@include \"../loop.tt\";");
+		write("bad.tt", "'ok' -> 'x';\nf[<x>] -> <y>;");
+
+		let mut ns = Namespace::new();
+		let (rules, warnings) = load_rules(dir.join("main.tt"), &mut ns).unwrap();
+		let names: Vec<&str> = rules.iter().map(|r| match r.lhs {
+			Atom(val) => ns.to_str(val).unwrap().as_str(),
+			_ => unreachable!(),
+		}).collect();
+		assert_eq!(names, vec!["f", "m1", "i1", "f", "i2", "m2", "l"]);
+		// The second copy of first.tt can never fire
+		assert_eq!(warnings.iter().map(|l| (l.rule, l.kind)).collect::<Vec<_>>(), vec![(3, LintKind::Unreachable)]);
+
+		let err = load_rules(dir.join("bad.tt"), &mut ns).unwrap_err();
+		assert_eq!(err.to_string(), format!("rule 1 at {}:2:1: the rhs uses <y> at [], which the lhs never binds", dir.join("bad.tt").display()));
+
+		match load_rules(dir.join("loop.tt"), &mut ns) {
+			Err(LoadError::Cycle(ref paths)) => assert_eq!(paths.len(), 3),
+			ref other => panic!("{:?}", other),
+		}
+		let err = parse_rules("@include \"x.tt\";", &mut ns).unwrap_err();
+		assert_eq!(err.pos.column, 1);
+		fs::remove_dir_all(&dir).unwrap();
+	}
+
+	#[test]
+	fn named_rules() {
+		let mut ns = Namespace::new();
+		let rules = parse_rules("'a' -> 'b';\n[disabled, note = \"x\"] fold_add: add['0', <x>] -> <x>;\nrenamed: 'c' -> 'd';", &mut ns).unwrap();
+		assert_eq!(rules.len(), 3);
+		assert_eq!(rules[0].describe(0), "rule 0 at 1:1");
+		assert_eq!(rules[1].describe(1), "rule `fold_add` at 2:1");
+		assert!(!rules[1].meta.enabled && rules[2].meta.enabled);
+		assert_eq!(rules[1].attribute("note"), Some(Some("x")));
+		assert_eq!(rules[1].attribute("disabled"), Some(None));
+		assert_eq!(rules[2].meta.name, Some("renamed".to_string()));
+		assert_eq!(parse_rules("name: ;", &mut ns).unwrap_err().msg, "Expected a pattern");
+		assert_eq!(parse_rules("[] 'a' -> 'b';", &mut ns).unwrap_err().msg, "Expected an attribute name");
+	}
+
+	#[test]
+	fn macros_expand_rule_families() {
+		// make_ttr_rules, with the list heads that build a Group written as @for
+		let src = "
+			<sequence>[Constructive[Group[<x>, <c>[ChildEnd[]]]]] -> <sequence>[Constructive[Group[<x>, <c>[]]]];
+			<sequence>[Constructive[Sequence[<x>, <c>[ChildEnd[]]]]] -> <sequence>[Constructive[Sequence[<x>, <c>[]]]];
+			<sequence>[Pattern[Disjunctor[<c>[ChildEnd[]]]]] -> <sequence>[Pattern[Disjunctor[<c>[]]]];
+			<sequence>[Pattern[Conjunctor[<c>[ChildEnd[]]]]] -> <sequence>[Pattern[Conjunctor[<c>[]]]];
+			<sequence>[string[<x>], &[<n>, !|[oper['['], <c>[ChildEnd[]]]]] -> <sequence>[Constructive[Atom[<x>]], <n>];
+			<sequence>[oper['<'], ident[<x>], oper['>'], &[<n>, !|[oper['['], <c>[ChildEnd[]]]]] -> <sequence>[Constructive[MatchPoint[<x>]], <n>];
+			<sequence>[oper['<'], oper['>'], &[<n>, !|[oper['['], <c>[ChildEnd[]]]]] -> <sequence>[Constructive[MatchPoint['']], <n>];
+			<sequence>[oper['['], oper[']']] -> <sequence>[Children[ChildEnd[]], oper[']']];
+			<sequence>[oper['['], |[Constructive[<x>], Pattern[<x>]]] -> <sequence>[Children[<x>, ChildEnd[]]];
+			<sequence>[<c>[ChildEnd[]], oper[','], |[Constructive[<x>], Pattern[<x>]]] -> <sequence>[<c>[<x>, ChildEnd[]]];
+
+			@let heads = [string[<x>], ident[<x>]];
+			@for $head in heads {
+				<sequence>[$head, &[<l>, <c>[ChildEnd[]]], oper[']']] -> <sequence>[Constructive[Group[<x>, <l>]]];
+			}
+
+			<sequence>[oper['<'], ident[<x>], oper['>'], &[<l>, <c>[ChildEnd[]]], oper[']']] -> <sequence>[Constructive[Sequence[<x>, <l>]]];
+			<sequence>[oper['<'], oper['>'], &[<l>, <c>[ChildEnd[]]], oper[']']] -> <sequence>[Constructive[Sequence['', <l>]]];
+			<sequence>[oper['('], ident[<x>], oper[')'], &[<l>, <c>[ChildEnd[]]], oper[']']] -> <sequence>[Constructive[Sequence[<x>, <l>]]];
+			<sequence>[oper['|'], &[<l>, <c>[ChildEnd[]]], oper[']']] -> <sequence>[Pattern[Disjunctor[<l>]]];
+			<sequence>[oper['&'], &[<l>, <c>[ChildEnd[]]], oper[']']] -> <sequence>[Pattern[Conjunctor[<l>]]];
+			<sequence>[oper['!'], |[Constructive[<x>], Pattern[<x>]]] -> <sequence>[Pattern[Negator[<x>]]];
+			<sequence>[|[Constructive[<x>], Pattern[<x>]], oper['-'], oper['>'], Constructive[<a>]] -> <sequence>[Rule[<x>, <a>]];
+			<sequence>[<c>[RuleEnd[]], Rule[<x>, <y>], oper[';']] -> <sequence>[<c>[Rule[<x>, <y>], RuleEnd[]]];
+			<sequence>[Rule[<x>, <y>], oper[';']] -> <sequence>[RuleSet[Rule[<x>, <y>], RuleEnd[]]];
+			document[<c>[RuleEnd[]]] -> document[<c>[]];
+		";
+		let mut ns = Namespace::new();
+		let expanded = parse_rules(src, &mut ns).unwrap();
+		let generated = make_ttr_rules(&mut ns);
+		assert_eq!(diff_rulesets(&generated, &expanded, &ns), Vec::<String>::new());
+
+		for (src, msg) in &[
+			("'a' -> $t;", "Expected a macro variable bound by @for"),
+			("@for $t in nope { }", "Unknown template list nope"),
+			("@for $t in [|['a']] { 'x' -> $t; }", "$t gives a rule whose rhs isn't an atom, match point, group or sequence"),
+			("@for $t in ['a'] { @include \"x.tt\"; }", "@include isn't allowed inside @for"),
+			("@macro x;", "Expected 'include', 'let' or 'for' after '@'"),
+			("@for $t in ['a'] { [note = \"ok\"] named: $t -> 'x'; }", "Rules inside @for can't be named"),
+		] {
+			assert_eq!(parse_rules(src, &mut ns).unwrap_err().msg, *msg);
+		}
+	}
+
+	#[test]
+	fn decompile_round_trips() {
+		let mut ns = Namespace::new();
+		let generated = make_ttr_rules(&mut ns);
+		let text = decompile(&generated, &ns).unwrap();
+		// ttr.tt is make_ttr_rules written out by hand, comments aside
+		let source: Vec<&str> = include_str!("../ttr.tt").lines().filter(|l| !l.is_empty() && !l.starts_with("/*")).collect();
+		assert_eq!(text.lines().collect::<Vec<&str>>(), source);
+		let reparsed = parse_rules(&text, &mut ns).unwrap();
+		assert_eq!(reparsed.len(), generated.len());
+		for (a, b) in generated.iter().zip(&reparsed) {
+			assert_eq!((&a.lhs, &a.rhs, a.meta.enabled), (&b.lhs, &b.rhs, b.meta.enabled));
+		}
+
+		let src = "[disabled, note = \"a \\\"b\\\"\"] tidy: 'it\\'s'[<>, |['\\n', !\"x y\"[]]] -> <s>[<>];\n";
+		let rules = parse_rules(src, &mut ns).unwrap();
+		let text = decompile(&rules, &ns).unwrap();
+		let again = parse_rules(&text, &mut ns).unwrap();
+		assert_eq!((&rules[0].lhs, &rules[0].rhs), (&again[0].lhs, &again[0].rhs));
+		assert_eq!((&rules[0].meta.name, &rules[0].meta.attributes, rules[0].meta.enabled), (&again[0].meta.name, &again[0].meta.attributes, again[0].meta.enabled));
+		assert_eq!(decompile(&again, &ns).unwrap(), text);
+
+		let spaced = ns.to_int("a b");
+		let err = decompile(&vec![Rule::new(MatchPoint(spaced), Atom(spaced)).named("bad")], &ns).unwrap_err();
+		assert_eq!(err.to_string(), "rule `bad`: \"a b\" can't be written as a match point name");
+		let mut keyed = Rule::new(Atom(spaced), Atom(spaced));
+		keyed.meta.attributes.push(("a b".to_string(), None));
+		assert_eq!(decompile(&vec![keyed], &ns).unwrap_err().msg, "\"a b\" can't be written as an attribute name");
+
+		// set_enabled without a "disabled" attribute, and the other way around
+		let mut toggled = parse_rules("off: 'a' -> 'b'; [disabled, x] on: 'c' -> 'd';", &mut ns).unwrap();
+		set_enabled(&mut toggled, "off", false);
+		set_enabled(&mut toggled, "on", true);
+		let text = decompile(&toggled, &ns).unwrap();
+		assert_eq!(text, "[disabled] off: 'a' -> 'b';\n[x] on: 'c' -> 'd';\n");
+		let again = parse_rules(&text, &mut ns).unwrap();
+		assert_eq!((again[0].meta.enabled, again[1].meta.enabled), (false, true));
+	}
 }